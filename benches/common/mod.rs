@@ -0,0 +1,17 @@
+use nearest_neighbors::{eval, groundtruth::GroundTruth, Algorithm, Metric, VectorID};
+
+pub const METRIC: Metric = Metric::SquaredEuclidean;
+
+/// Print recall@k of `index` under `metric`, since Criterion only reports time.
+pub fn report_recall(
+    id: &str,
+    index: &dyn Algorithm,
+    data: &[(VectorID, Vec<f32>)],
+    queries: &[Vec<f32>],
+    ground_truth: &GroundTruth,
+    k: usize,
+    metric: Metric,
+) {
+    let evaluation = eval::evaluate(index, data, queries, ground_truth, k, metric);
+    println!("{}: recall@{} = {:.4}", id, k, evaluation.recall);
+}
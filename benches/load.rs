@@ -1,28 +1,33 @@
-use std::env;
-
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 
-use nearest_neighbors::load_dataset;
+use nearest_neighbors::synthetic::{self, Synthetic};
 
-use nearest_neighbors::VectorID;
 use nearest_neighbors::{exact::Exact, kdtree::KDTree, lsh::LSH, nsw::NSW, vptree::VPTree};
+use nearest_neighbors::{Algorithm, VectorID};
 
 fn bench_load(c: &mut Criterion) {
     let mut group = c.benchmark_group("Method::Load");
     group.sample_size(10);
 
-    let path = env::var("DATASET_PATH").expect("env DATASET_PATH should be set");
-    let mut data: Vec<(VectorID, Vec<f32>)> = load_dataset(&path)
-        .unwrap()
-        .into_iter()
-        .enumerate()
-        .map(|(idx, (_str, vector))| (idx, vector))
-        .collect();
+    // falls back to generated data if DATASET_PATH isn't set
+    let mut data: Vec<(VectorID, Vec<f32>)> =
+        synthetic::load_env_or_generate(Synthetic::Clusters, 20_000, 32, 0);
 
     data.truncate(200_000);
 
     let l = data.len();
 
+    let indexes: [(&str, Box<dyn Algorithm>); 5] = [
+        ("Exact", Box::new(Exact::load(&data))),
+        ("KDTree", Box::new(KDTree::load(&data))),
+        ("VPTree", Box::new(VPTree::load(&data))),
+        ("LSH", Box::new(LSH::load(&data))),
+        ("NSW", Box::new(NSW::load(&data))),
+    ];
+    for (name, index) in indexes {
+        println!("{} memory: {}", name, index.memory_usage());
+    }
+
     group.bench_function(BenchmarkId::new("Exact", l), |b| {
         b.iter(|| Exact::load(&data))
     });
@@ -0,0 +1,141 @@
+use std::fmt::Display;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::seq::SliceRandom;
+
+use nearest_neighbors::groundtruth::GroundTruth;
+use nearest_neighbors::synthetic::{self, Synthetic};
+use nearest_neighbors::{
+    exact::Exact,
+    kdtree::KDTree,
+    lsh::{LSHConfig, LSH},
+    nsw::NSW,
+    vptree::{VPTree, VPTreeConfig},
+    Algorithm, Metric, VectorID,
+};
+
+mod common;
+
+use common::{report_recall, METRIC};
+
+const K: usize = 10;
+const NUM_QUERIES: usize = 100;
+
+/// NSW and LSH builds are too slow to run at every size
+const MAX_APPROXIMATE_N: usize = 100_000;
+
+/// KDTree and NSW only search squared L2, so they're left out for other metrics.
+fn indexes(
+    data: &[(VectorID, Vec<f32>)],
+    metric: Metric,
+) -> Vec<(&'static str, Box<dyn Algorithm>)> {
+    let l2 = metric == Metric::SquaredEuclidean;
+    let mut indexes: Vec<(&'static str, Box<dyn Algorithm>)> =
+        vec![("Exact", Box::new(Exact::with_metric(data, metric)))];
+    if l2 {
+        indexes.push(("KDTree", Box::new(KDTree::load(data))));
+    }
+    indexes.push((
+        "VPTree",
+        Box::new(VPTree::with_metric(data, metric, VPTreeConfig::default())),
+    ));
+    if data.len() <= MAX_APPROXIMATE_N {
+        indexes.push((
+            "LSH",
+            Box::new(LSH::with_metric(data, metric, LSHConfig::default())),
+        ));
+        if l2 {
+            indexes.push(("NSW", Box::new(NSW::load(data))));
+        }
+    }
+    indexes
+}
+
+/// Search every index built on all but the last `NUM_QUERIES` vectors of
+/// `data`, using those last vectors as queries.
+fn bench_dataset(
+    c: &mut Criterion,
+    group_name: &str,
+    parameter: impl Display,
+    metric: Metric,
+    mut data: Vec<(VectorID, Vec<f32>)>,
+) {
+    let queries: Vec<Vec<f32>> = data
+        .split_off(data.len() - NUM_QUERIES)
+        .into_iter()
+        .map(|(_, v)| v)
+        .collect();
+    let data = &data;
+    let ground_truth = GroundTruth::compute(data, &queries, K, metric);
+
+    let mut group = c.benchmark_group(group_name);
+    group.sample_size(10);
+    let mut rng = rand::thread_rng();
+
+    for (name, index) in indexes(data, metric) {
+        report_recall(
+            &format!("{}/{}/{}", group_name, name, parameter),
+            index.as_ref(),
+            data,
+            &queries,
+            &ground_truth,
+            K,
+            metric,
+        );
+        group.bench_function(BenchmarkId::new(name, &parameter), |b| {
+            b.iter(|| index.search(queries.choose(&mut rng).unwrap(), K))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_size(c: &mut Criterion) {
+    for n in [10_000, 100_000, 1_000_000] {
+        let data = Synthetic::Uniform.generate(n + NUM_QUERIES, 16, 0);
+        bench_dataset(c, "Scaling::Size", n, METRIC, data);
+    }
+}
+
+fn bench_dimension(c: &mut Criterion) {
+    for dim in [2, 8, 32, 128, 960] {
+        let data = Synthetic::Uniform.generate(50_000 + NUM_QUERIES, dim, 0);
+        bench_dataset(c, "Scaling::Dimension", dim, METRIC, data);
+    }
+}
+
+fn bench_intrinsic_dimension(c: &mut Criterion) {
+    // same ambient dimension, so only the structure of the data changes
+    for intrinsic_dim in [2, 4, 8, 16, 32] {
+        let data = synthetic::manifold(50_000 + NUM_QUERIES, 128, intrinsic_dim, 0);
+        bench_dataset(
+            c,
+            "Scaling::IntrinsicDimension",
+            intrinsic_dim,
+            METRIC,
+            data,
+        );
+    }
+}
+
+fn bench_metric(c: &mut Criterion) {
+    for metric in [
+        Metric::SquaredEuclidean,
+        Metric::Euclidean,
+        Metric::Cosine,
+        Metric::Angular,
+        Metric::Manhattan,
+    ] {
+        let data = Synthetic::Uniform.generate(50_000 + NUM_QUERIES, 16, 0);
+        bench_dataset(c, "Scaling::Metric", format!("{metric:?}"), metric, data);
+    }
+}
+
+criterion_group!(
+    benches,
+    bench_size,
+    bench_dimension,
+    bench_intrinsic_dimension,
+    bench_metric
+);
+criterion_main!(benches);
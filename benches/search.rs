@@ -1,23 +1,23 @@
-use std::env;
-
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use rand::seq::SliceRandom;
 
-use nearest_neighbors::{load_dataset, Algorithm};
+use nearest_neighbors::groundtruth::GroundTruth;
+use nearest_neighbors::synthetic::{self, Synthetic};
+use nearest_neighbors::Algorithm;
 
 use nearest_neighbors::{
     exact::Exact, kdtree::KDTree, lsh::LSH, nsw::NSW, vptree::VPTree, VectorID,
 };
 
+mod common;
+
+use common::{report_recall, METRIC};
+
 fn bench_search(c: &mut Criterion) {
     let mut group = c.benchmark_group("Method::Search");
-    let path = env::var("DATASET_PATH").expect("env DATASET_PATH should be set");
-    let data: Vec<(VectorID, Vec<f32>)> = load_dataset(&path)
-        .unwrap()
-        .into_iter()
-        .enumerate()
-        .map(|(idx, (_str, vector))| (idx, vector))
-        .collect();
+    // falls back to generated data if DATASET_PATH isn't set
+    let data: Vec<(VectorID, Vec<f32>)> =
+        synthetic::load_env_or_generate(Synthetic::Clusters, 20_000, 32, 0);
     println!("Loaded dataset. Found {} vectors.", data.len());
 
     let query_keys: Vec<Vec<f32>> = data
@@ -26,16 +26,32 @@ fn bench_search(c: &mut Criterion) {
         .collect();
 
     let (exact, kdtree, vptree, lsh, nsw) = (
-        Exact::load(&data),
+        Exact::with_metric(&data, METRIC),
         KDTree::load(&data),
         VPTree::load(&data),
         LSH::load(&data),
         NSW::load(&data),
     );
 
+    // recall is measured on a subset of the queries to keep the setup quick
+    let recall_queries = &query_keys[..query_keys.len().min(200)];
+    let ground_truth = GroundTruth::compute(&data, recall_queries, 100, METRIC);
+    let indexes: [(&str, &dyn Algorithm); 5] = [
+        ("Exact", &exact),
+        ("KDTree", &kdtree),
+        ("VPTree", &vptree),
+        ("LSH", &lsh),
+        ("NSW", &nsw),
+    ];
+
     let mut rng = rand::thread_rng();
 
     for k in [1, 5, 10, 20, 50, 100].iter() {
+        for (name, index) in indexes {
+            let id = format!("Method::Search/{}/{}", name, k);
+            report_recall(&id, index, &data, recall_queries, &ground_truth, *k, METRIC);
+        }
+
         group.bench_with_input(BenchmarkId::new("Exact", k), k, |b, k| {
             b.iter(|| exact.search(query_keys.choose(&mut rng).unwrap(), *k))
         });
@@ -0,0 +1,31 @@
+"""Convert the JSON results of `nearest-neighbors ann` to ann-benchmarks'
+HDF5 result files, next to the JSON files.
+
+usage: python scripts/ann_results_to_hdf5.py results/
+"""
+
+import json
+import pathlib
+import sys
+
+import h5py
+import numpy as np
+
+ATTRS = [
+    "algo", "name", "dataset", "distance", "count", "build_time", "index_size",
+    "best_search_time", "candidates", "expect_extra", "batch_mode", "run_count",
+]
+
+for path in pathlib.Path(sys.argv[1]).rglob("*.json"):
+    result = json.loads(path.read_text())
+    count = result["count"]
+    with h5py.File(path.with_suffix(".hdf5"), "w") as f:
+        for attr in ATTRS:
+            f.attrs[attr] = result[attr]
+        f.create_dataset("times", data=np.array(result["times"]))
+        neighbors = f.create_dataset("neighbors", (len(result["times"]), count), "i")
+        distances = f.create_dataset("distances", (len(result["times"]), count), "f")
+        for i, (ids, dists) in enumerate(zip(result["neighbors"], result["distances"])):
+            neighbors[i] = ids + [-1] * (count - len(ids))
+            distances[i] = dists + [float("inf")] * (count - len(dists))
+    print(path.with_suffix(".hdf5"))
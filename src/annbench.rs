@@ -0,0 +1,351 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::{
+    eval,
+    groundtruth::GroundTruth,
+    load_dataset,
+    sweep::{self, Build, Grid},
+    Algorithm, Metric, VectorID,
+};
+
+/// Train vectors, test queries and their true neighbors, as distributed by
+/// ann-benchmarks and the TEXMEX corpus.
+pub struct AnnDataset {
+    pub name: String,
+    pub train: Vec<(VectorID, Vec<f32>)>,
+    pub test: Vec<Vec<f32>>,
+    pub neighbors: GroundTruth,
+}
+
+impl AnnDataset {
+    /// Vectors are read from `.fvecs` files or the crate's own text format,
+    /// depending on the extension. Neighbors are read from `.ivecs`.
+    pub fn load(name: &str, train: &str, test: &str, neighbors: &str) -> io::Result<Self> {
+        let train = load_vectors(train)?.into_iter().enumerate().collect();
+        let test = load_vectors(test)?;
+        let neighbors = GroundTruth::load_ivecs(neighbors)?;
+
+        if neighbors.neighbors.len() != test.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} test vectors but {} rows of neighbors",
+                    test.len(),
+                    neighbors.neighbors.len()
+                ),
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            train,
+            test,
+            neighbors,
+        })
+    }
+}
+
+fn load_vectors(path: &str) -> io::Result<Vec<Vec<f32>>> {
+    if path.ends_with(".fvecs") {
+        load_fvecs(path)
+    } else {
+        Ok(load_dataset(path)?.into_iter().map(|(_, v)| v).collect())
+    }
+}
+
+/// Load vectors from an `.fvecs` file, where every row is a little-endian
+/// `i32` dimension followed by that many `f32` components.
+pub fn load_fvecs(path: &str) -> io::Result<Vec<Vec<f32>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut vectors = Vec::new();
+    let mut buf = [0u8; 4];
+
+    loop {
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let dim = u32::from_le_bytes(buf) as usize;
+        let mut row = vec![0u8; dim * 4];
+        reader.read_exact(&mut row)?;
+        vectors.push(
+            row.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        );
+    }
+
+    Ok(vectors)
+}
+
+/// One run of one index configuration, with the attributes and datasets of
+/// an ann-benchmarks result file.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnnResult {
+    pub algo: String,
+    /// algorithm and parameters, unique within a run
+    pub name: String,
+    pub dataset: String,
+    /// "euclidean", "angular" or "manhattan"
+    pub distance: &'static str,
+    /// no. of neighbors requested per query
+    pub count: usize,
+    /// seconds
+    pub build_time: f64,
+    /// kB, as reported by the index
+    pub index_size: f64,
+    /// mean seconds per query of the fastest run
+    pub best_search_time: f64,
+    /// mean no. of distance computations per query
+    pub candidates: f64,
+    pub expect_extra: bool,
+    pub batch_mode: bool,
+    pub run_count: usize,
+    /// seconds per query, of the fastest run
+    pub times: Vec<f64>,
+    pub neighbors: Vec<Vec<VectorID>>,
+    /// `distance` from each query to its neighbors
+    pub distances: Vec<Vec<f32>>,
+}
+
+impl AnnResult {
+    /// Write the result under `dir` following the ann-benchmarks layout,
+    /// `<dataset>/<count>/<algo>/<name>.json`, and return the file path.
+    pub fn write(&self, dir: &str) -> io::Result<PathBuf> {
+        let dir = Path::new(dir)
+            .join(&self.dataset)
+            .join(self.count.to_string())
+            .join(&self.algo);
+        fs::create_dir_all(&dir)?;
+
+        let file_name: String = self
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = dir.join(format!("{file_name}.json"));
+
+        let mut writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(path)
+    }
+}
+
+/// Runs every configuration of a grid over a dataset, one query at a time,
+/// like ann-benchmarks' non-batch mode.
+pub struct AnnRunner<'a> {
+    pub dataset: &'a AnnDataset,
+    pub k: usize,
+    pub metric: Metric,
+    /// the query set is searched this many times and the fastest run is kept
+    pub runs: usize,
+}
+
+impl AnnRunner<'_> {
+    pub fn run(&self, grid: &Grid, mut on_result: impl FnMut(AnnResult)) {
+        let train = &self.dataset.train;
+        sweep::for_each_index(grid, train, self.metric, |algo, params, build, index| {
+            on_result(self.result(algo, params, build, index));
+        });
+    }
+
+    /// Mean fraction of the true `k` nearest neighbors that `result`
+    /// returned, counted by distance like [`eval::evaluate`] does.
+    pub fn recall(&self, result: &AnnResult) -> f64 {
+        let queries = &self.dataset.test;
+        let mut recall = 0.0;
+        for ((query, distances), truth) in queries
+            .iter()
+            .zip(&result.distances)
+            .zip(&self.dataset.neighbors.neighbors)
+        {
+            let truth = &truth[..self.k.min(truth.len())];
+            recall += match truth.last() {
+                Some(kth) => {
+                    let kth = self.reported_distance(query, &self.dataset.train[*kth].1);
+                    eval::query_recall(distances, kth, truth.len())
+                }
+                None => 1.0,
+            };
+        }
+        recall / queries.len().max(1) as f64
+    }
+
+    fn result(
+        &self,
+        algo: &str,
+        params: String,
+        build: &Build,
+        index: &dyn Algorithm,
+    ) -> AnnResult {
+        let queries = &self.dataset.test;
+        let runs = self.runs.max(1);
+
+        let mut best: Option<(f64, Vec<f64>, Vec<Vec<VectorID>>)> = None;
+        for _ in 0..runs {
+            let mut times = Vec::with_capacity(queries.len());
+            let mut neighbors = Vec::with_capacity(queries.len());
+            for query in queries {
+                let start = Instant::now();
+                let result = index.search(query, self.k);
+                times.push(start.elapsed().as_secs_f64());
+                neighbors.push(result);
+            }
+
+            let total: f64 = times.iter().sum();
+            if best
+                .as_ref()
+                .is_none_or(|(best_total, _, _)| total < *best_total)
+            {
+                best = Some((total, times, neighbors));
+            }
+        }
+        let (total, times, neighbors) = best.unwrap();
+
+        let distances = queries
+            .iter()
+            .zip(&neighbors)
+            .map(|(query, ids)| {
+                ids.iter()
+                    .map(|id| self.reported_distance(query, &self.dataset.train[*id].1))
+                    .collect()
+            })
+            .collect();
+
+        let candidates = queries
+            .iter()
+            .map(|query| {
+                index
+                    .search_with_stats(query, self.k)
+                    .1
+                    .distance_computations
+            })
+            .sum::<usize>() as f64
+            / queries.len().max(1) as f64;
+
+        let name = if params.is_empty() {
+            algo.to_owned()
+        } else {
+            format!("{algo}({params})")
+        };
+
+        AnnResult {
+            algo: algo.to_owned(),
+            name,
+            dataset: self.dataset.name.clone(),
+            distance: self.distance_name(),
+            count: self.k,
+            build_time: build.time.as_secs_f64(),
+            index_size: index.memory_usage().total() as f64 / 1024.0,
+            best_search_time: total / queries.len().max(1) as f64,
+            candidates,
+            expect_extra: false,
+            batch_mode: false,
+            run_count: runs,
+            times,
+            neighbors,
+            distances,
+        }
+    }
+
+    fn distance_name(&self) -> &'static str {
+        match self.metric {
+            Metric::SquaredEuclidean | Metric::Euclidean => "euclidean",
+            Metric::Cosine | Metric::Angular => "angular",
+            Metric::Manhattan => "manhattan",
+        }
+    }
+
+    /// ann-benchmarks reports plain, not squared, Euclidean distances and
+    /// cosine distances as "angular".
+    fn reported_distance(&self, x: &[f32], y: &[f32]) -> f32 {
+        match self.metric {
+            Metric::SquaredEuclidean | Metric::Euclidean => Metric::Euclidean.distance(x, y),
+            Metric::Cosine | Metric::Angular => Metric::Cosine.distance(x, y),
+            Metric::Manhattan => Metric::Manhattan.distance(x, y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fvecs_and_results() {
+        let dir = std::env::temp_dir().join(format!("nn-annbench-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+
+        let mut train: Vec<Vec<f32>> = (0..20).map(|i| vec![i as f32, 0.0]).collect();
+        // a duplicate of 2, which is as good a third neighbor of the first query
+        train.push(vec![2.0, 0.0]);
+        let test = vec![vec![3.1, 0.0], vec![17.6, 0.0]];
+        for (name, vectors) in [("train.fvecs", &train), ("test.fvecs", &test)] {
+            let mut file = File::create(path(name)).unwrap();
+            for v in vectors.iter() {
+                file.write_all(&(v.len() as u32).to_le_bytes()).unwrap();
+                for x in v {
+                    file.write_all(&x.to_le_bytes()).unwrap();
+                }
+            }
+        }
+        let truth = GroundTruth {
+            neighbors: vec![vec![3, 4, 2], vec![18, 17, 19]],
+            distances: Vec::new(),
+        };
+        truth.save_ivecs(&path("neighbors.ivecs")).unwrap();
+
+        let dataset = AnnDataset::load(
+            "line",
+            &path("train.fvecs"),
+            &path("test.fvecs"),
+            &path("neighbors.ivecs"),
+        )
+        .unwrap();
+        assert_eq!(load_fvecs(&path("train.fvecs")).unwrap(), train);
+
+        let runner = AnnRunner {
+            dataset: &dataset,
+            k: 3,
+            metric: Metric::SquaredEuclidean,
+            runs: 2,
+        };
+        let grid = Grid {
+            algorithms: vec!["exact".to_owned(), "kdtree".to_owned()],
+            kdtree_leaf_size: vec![4],
+            kdtree_epsilon: vec![0.0],
+            ..Grid::default()
+        };
+        let mut results = Vec::new();
+        runner.run(&grid, |result| results.push(result));
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].name, "kdtree(leaf_size=4 epsilon=0)");
+        for result in &results {
+            assert_eq!(runner.recall(result), 1.0);
+            assert_eq!(result.times.len(), 2);
+            assert!((result.distances[0][0] - 0.1).abs() < 1e-5);
+
+            let written = result.write(&path("results")).unwrap();
+            assert!(written.starts_with(path("results/line/3")));
+            assert!(written.exists());
+        }
+
+        let mut missed = results[0].clone();
+        missed.neighbors[0] = vec![3, 4, 5];
+        missed.distances[0] = vec![0.1, 0.9, 1.9];
+        assert!((runner.recall(&missed) - 5.0 / 6.0).abs() < 1e-9);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rayon::prelude::*;
+
+use crate::index::{Index, Neighbor, Query};
+
+/// Marks the output line of a query that failed, followed by the reason
+pub const ERROR_MARKER: &str = "ERROR:";
+
+/// Read a query file with one query per line, either a label or a vector.
+/// Every line gives one entry, so a line that isn't a query (including a
+/// blank one) gives an error.
+pub fn read_queries(path: &str) -> io::Result<Vec<Result<Query, String>>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    reader
+        .lines()
+        .map(|line| line.map(|line| Query::parse(&line)))
+        .collect()
+}
+
+/// Run every query against the index in parallel, keeping the input order.
+/// Queries that failed to parse keep their error. `progress` is called with
+/// the number of finished queries after each one.
+pub fn search_all<'a, F>(
+    index: &'a Index,
+    queries: &[Result<Query, String>],
+    k: usize,
+    progress: F,
+) -> Vec<Result<Vec<Neighbor<'a>>, String>>
+where
+    F: Fn(usize) + Sync,
+{
+    let done = AtomicUsize::new(0);
+
+    queries
+        .par_iter()
+        .map(|query| {
+            let result = query
+                .as_ref()
+                .map_err(Clone::clone)
+                .and_then(|query| index.search(query, k));
+            progress(done.fetch_add(1, Ordering::Relaxed) + 1);
+            result
+        })
+        .collect()
+}
+
+/// Write one line of space separated neighbor labels per query, so output
+/// lines match input lines. A failed query's line is [`ERROR_MARKER`] and
+/// the reason, which tells it apart from a query without neighbors.
+pub fn write_results(path: &str, results: &[Result<Vec<Neighbor>, String>]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    for result in results {
+        match result {
+            Ok(neighbors) => {
+                let labels: Vec<&str> = neighbors.iter().map(|n| n.label).collect();
+                writeln!(writer, "{}", labels.join(" "))?;
+            }
+            Err(e) => writeln!(writer, "{ERROR_MARKER} {e}")?,
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::Exact;
+
+    #[test]
+    fn test_one_output_line_per_input_line() {
+        let data = vec![
+            ("a".to_owned(), vec![1.0, 0.0]),
+            ("b".to_owned(), vec![0.0, 1.0]),
+        ];
+        let ids: Vec<_> = data
+            .iter()
+            .enumerate()
+            .map(|(i, (_, v))| (i, v.clone()))
+            .collect();
+        let index = Index::new(Box::new(Exact::load(&ids)), "exact", data);
+
+        let dir = env::temp_dir();
+        let input = dir.join(format!("nn-batch-{}.in", std::process::id()));
+        let output = dir.join(format!("nn-batch-{}.out", std::process::id()));
+        fs::write(&input, "a\n\nmissing\n0.9 0.1 0.0\n").unwrap();
+
+        let queries = read_queries(input.to_str().unwrap()).unwrap();
+        let results = search_all(&index, &queries, 1, |_| {});
+        write_results(output.to_str().unwrap(), &results).unwrap();
+
+        let lines: Vec<String> = fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "a");
+        assert_eq!(lines[1], format!("{ERROR_MARKER} empty query"));
+        assert!(lines[2].starts_with(ERROR_MARKER));
+        assert!(lines[3].starts_with(ERROR_MARKER));
+
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+}
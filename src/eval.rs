@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{groundtruth::GroundTruth, stats::SearchStats, Algorithm, Metric, VectorID};
+
+/// Accuracy of an algorithm over a query set, compared to the ground truth.
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub k: usize,
+    pub queries: usize,
+    /// Mean fraction of the true k nearest neighbors that were returned.
+    /// Any result as close as the kth true neighbor counts, since which of
+    /// several tied ids is returned is arbitrary.
+    pub recall: f64,
+    /// Mean reciprocal rank of the first result as close as the true nearest
+    /// neighbor
+    pub mrr: f64,
+    /// Mean ratio of the summed distances of the results to those of the
+    /// true neighbors. 1.0 is perfect.
+    pub distance_ratio: f64,
+    pub search_time: Duration,
+}
+
+/// Run every query through `algorithm` and compare its results with the
+/// first `k` entries of the ground truth.
+pub fn evaluate(
+    algorithm: &dyn Algorithm,
+    data: &[(VectorID, Vec<f32>)],
+    queries: &[Vec<f32>],
+    ground_truth: &GroundTruth,
+    k: usize,
+    metric: Metric,
+) -> Evaluation {
+    assert_eq!(queries.len(), ground_truth.neighbors.len());
+
+    let start = Instant::now();
+    let results: Vec<Vec<VectorID>> = queries.iter().map(|q| algorithm.search(q, k)).collect();
+    let search_time = start.elapsed();
+
+    let vectors: HashMap<VectorID, &[f32]> =
+        data.iter().map(|(id, v)| (*id, v.as_slice())).collect();
+    let total_distance = |query: &[f32], ids: &[VectorID]| -> f32 {
+        ids.iter()
+            .map(|id| metric.distance(query, vectors[id]))
+            .sum()
+    };
+
+    let (mut recall, mut mrr, mut ratio, mut ratio_count) = (0.0, 0.0, 0.0, 0);
+
+    for ((query, result), truth) in queries.iter().zip(&results).zip(&ground_truth.neighbors) {
+        let truth = &truth[..k.min(truth.len())];
+        if truth.is_empty() {
+            recall += 1.0;
+            mrr += 1.0;
+            continue;
+        }
+
+        let distances: Vec<f32> = result
+            .iter()
+            .map(|id| metric.distance(query, vectors[id]))
+            .collect();
+        let kth = metric.distance(query, vectors[&truth[truth.len() - 1]]);
+        recall += query_recall(&distances, kth, truth.len());
+
+        let nearest = metric.distance(query, vectors[&truth[0]]);
+        if let Some(rank) = distances.iter().position(|&d| d <= nearest) {
+            mrr += 1.0 / (rank + 1) as f64;
+        }
+
+        // queries whose true neighbors are all at distance 0 have no defined ratio
+        let true_distance = total_distance(query, truth);
+        if true_distance > 0.0 && !result.is_empty() {
+            // pad missing results with the worst returned distance
+            let found: f32 = distances.iter().sum();
+            let worst = distances.iter().copied().fold(f32::MIN, f32::max);
+            let missing = truth.len().saturating_sub(result.len()) as f32;
+            ratio += ((found + missing * worst) / true_distance) as f64;
+            ratio_count += 1;
+        }
+    }
+
+    let n = queries.len().max(1) as f64;
+    Evaluation {
+        k,
+        queries: queries.len(),
+        recall: recall / n,
+        mrr: mrr / n,
+        distance_ratio: if ratio_count == 0 {
+            1.0
+        } else {
+            ratio / ratio_count as f64
+        },
+        search_time,
+    }
+}
+
+/// Recall of one query whose results are at `distances` and whose
+/// `true_count` true neighbors are at most `kth` away. Any result as close
+/// as the kth true neighbor counts, since which of several tied ids is
+/// returned is arbitrary.
+pub fn query_recall(distances: &[f32], kth: f32, true_count: usize) -> f64 {
+    let hits = distances.iter().filter(|&&d| d <= kth).count();
+    hits.min(true_count) as f64 / true_count as f64
+}
+
+/// Search stats summed over all queries. Kept out of `evaluate` so that the
+/// counting doesn't affect the measured search time.
+pub fn total_stats(algorithm: &dyn Algorithm, queries: &[Vec<f32>], k: usize) -> SearchStats {
+    let mut total = SearchStats::default();
+    for query in queries {
+        total += algorithm.search_with_stats(query, k).1;
+    }
+    total
+}
+
+impl Evaluation {
+    pub fn qps(&self) -> f64 {
+        self.queries as f64 / self.search_time.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "recall@{}: {:.4}, MRR: {:.4}, distance ratio: {:.4}, {:.1} queries/s over {} queries",
+            self.k,
+            self.recall,
+            self.mrr,
+            self.distance_ratio,
+            self.qps(),
+            self.queries
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exact::Exact;
+
+    #[test]
+    fn test_exact_is_perfect() {
+        let data: Vec<(VectorID, Vec<f32>)> = (0..50)
+            .map(|i| (i, vec![i as f32, (i * i % 7) as f32]))
+            .collect();
+        let queries = vec![vec![3.2, 1.0], vec![40.0, 6.0], vec![-5.0, 0.0]];
+
+        let truth = GroundTruth::compute(&data, &queries, 5, Metric::SquaredEuclidean);
+        let exact = Exact::with_metric(&data, Metric::SquaredEuclidean);
+        let eval = evaluate(&exact, &data, &queries, &truth, 5, Metric::SquaredEuclidean);
+
+        assert_eq!(eval.recall, 1.0);
+        assert_eq!(eval.mrr, 1.0);
+        assert!((eval.distance_ratio - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_tied_neighbors_count_as_found() {
+        // every vector appears twice, under ids i and i + 10
+        let data: Vec<(VectorID, Vec<f32>)> = (0..20).map(|i| (i, vec![(i % 10) as f32])).collect();
+        let queries = vec![vec![2.1]];
+        let exact = Exact::with_metric(&data, Metric::SquaredEuclidean);
+        let found = exact.search(&queries[0], 1);
+
+        // ground truth that picked the other twin
+        let truth = GroundTruth {
+            neighbors: vec![found.iter().map(|id| (id + 10) % 20).collect()],
+            distances: Vec::new(),
+        };
+        let eval = evaluate(&exact, &data, &queries, &truth, 1, Metric::SquaredEuclidean);
+
+        assert_eq!(eval.recall, 1.0);
+        assert_eq!(eval.mrr, 1.0);
+    }
+}
@@ -1,28 +1,59 @@
-use crate::{cosine_similarity, Algorithm, LimitedHeap, OrdItem, VectorID};
+use crate::{
+    memory::{vec_bytes, MemoryUsage},
+    stats::SearchStats,
+    Algorithm, LimitedHeap, Metric, OrdItem, VectorID,
+};
 
 pub struct Exact {
     data: Vec<(VectorID, Vec<f32>)>,
+    metric: Metric,
 }
 
 impl Algorithm for Exact {
     fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
-        let mut k_min_heap: LimitedHeap<OrdItem<VectorID>> = LimitedHeap::new(k);
+        self.search_with_distances(query, k)
+            .into_iter()
+            .map(|OrdItem(_, id)| id)
+            .collect()
+    }
 
-        for (key, b) in &self.data {
-            let cosine_similarity: f32 = cosine_similarity(query, b);
-            k_min_heap.push(OrdItem(-1.0 * cosine_similarity, *key));
-        }
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats) {
+        let stats = SearchStats {
+            distance_computations: self.data.len(),
+            ..Default::default()
+        };
+        (self.search(query, k), stats)
+    }
 
-        let mut v: Vec<&OrdItem<VectorID>> = k_min_heap.iter().collect();
-        v.sort();
-        v.into_iter().map(|&OrdItem(_, id)| id).collect()
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            vectors: vec_bytes(&self.data)
+                + self.data.iter().map(|(_, v)| vec_bytes(v)).sum::<usize>(),
+            ..Default::default()
+        }
     }
 }
 
 impl Exact {
     pub fn load(data: &[(VectorID, Vec<f32>)]) -> Self {
+        Self::with_metric(data, Metric::Cosine)
+    }
+
+    pub fn with_metric(data: &[(VectorID, Vec<f32>)], metric: Metric) -> Self {
         Self {
             data: data.to_vec(),
+            metric,
         }
     }
+
+    /// The `k` closest vectors to `query` along with their distances, closest first.
+    pub fn search_with_distances(&self, query: &[f32], k: usize) -> Vec<OrdItem<VectorID>> {
+        let mut k_min_heap: LimitedHeap<OrdItem<VectorID>> = LimitedHeap::new(k);
+
+        for (key, b) in &self.data {
+            k_min_heap.push(OrdItem(self.metric.distance(query, b), *key));
+        }
+
+        k_min_heap.consume().into_sorted_vec()
+    }
 }
@@ -0,0 +1,350 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use rayon::prelude::*;
+
+use crate::{exact::Exact, Metric, OrdItem, VectorID};
+
+const MAGIC: &[u8; 4] = b"NNGT";
+const VERSION: u32 = 1;
+
+/// True nearest neighbors of each query and their distances, closest first.
+#[derive(Debug, Clone)]
+pub struct GroundTruth {
+    pub neighbors: Vec<Vec<VectorID>>,
+    /// Empty when loaded from a file without distances, like `.ivecs`
+    pub distances: Vec<Vec<f32>>,
+}
+
+/// Identifies what a ground truth was computed from, so that a cached copy is
+/// only reused for the same dataset, queries and metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub dataset: u64,
+    pub queries: u64,
+    pub metric: Metric,
+}
+
+impl Fingerprint {
+    pub fn new(data: &[(VectorID, Vec<f32>)], queries: &[Vec<f32>], metric: Metric) -> Self {
+        let mut dataset = Fnv::new();
+        dataset.write_u64(data.len() as u64);
+        for (id, vector) in data {
+            dataset.write_u64(*id as u64);
+            dataset.write_vector(vector);
+        }
+
+        let mut query_hash = Fnv::new();
+        query_hash.write_u64(queries.len() as u64);
+        for query in queries {
+            query_hash.write_vector(query);
+        }
+
+        Self {
+            dataset: dataset.0,
+            queries: query_hash.0,
+            metric,
+        }
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across builds.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_vector(&mut self, vector: &[f32]) {
+        self.write_u64(vector.len() as u64);
+        vector.iter().for_each(|x| self.write(&x.to_le_bytes()));
+    }
+}
+
+impl GroundTruth {
+    /// Compute the `k` true nearest neighbors of every query in parallel using `Exact`.
+    pub fn compute(
+        data: &[(VectorID, Vec<f32>)],
+        queries: &[Vec<f32>],
+        k: usize,
+        metric: Metric,
+    ) -> Self {
+        let exact = Exact::with_metric(data, metric);
+        let (neighbors, distances) = queries
+            .par_iter()
+            .map(|query| {
+                exact
+                    .search_with_distances(query, k)
+                    .into_iter()
+                    .map(|OrdItem(dist, id)| (id, dist))
+                    .unzip()
+            })
+            .unzip();
+        Self {
+            neighbors,
+            distances,
+        }
+    }
+
+    /// Reuse the ground truth cached at `path` if it was computed from the
+    /// same dataset, queries and metric with at least `k` neighbors. Otherwise
+    /// compute it and overwrite the cache.
+    pub fn load_or_compute(
+        path: &str,
+        data: &[(VectorID, Vec<f32>)],
+        queries: &[Vec<f32>],
+        k: usize,
+        metric: Metric,
+    ) -> io::Result<Self> {
+        let fingerprint = Fingerprint::new(data, queries, metric);
+
+        if let Ok((mut cached, cached_fingerprint)) = Self::load(path) {
+            if cached_fingerprint == fingerprint && cached.k() >= k.min(data.len()) {
+                cached.truncate(k);
+                return Ok(cached);
+            }
+        }
+
+        let ground_truth = Self::compute(data, queries, k, metric);
+        ground_truth.save(path, &fingerprint)?;
+        Ok(ground_truth)
+    }
+
+    /// No. of neighbors stored per query
+    pub fn k(&self) -> usize {
+        self.neighbors.iter().map(Vec::len).min().unwrap_or(0)
+    }
+
+    pub fn truncate(&mut self, k: usize) {
+        self.neighbors.iter_mut().for_each(|row| row.truncate(k));
+        self.distances.iter_mut().for_each(|row| row.truncate(k));
+    }
+
+    /// Save in a binary format headed by the fingerprint it was computed from.
+    pub fn save(&self, path: &str, fingerprint: &Fingerprint) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&[metric_code(fingerprint.metric)])?;
+        writer.write_all(&fingerprint.dataset.to_le_bytes())?;
+        writer.write_all(&fingerprint.queries.to_le_bytes())?;
+        writer.write_all(&(self.neighbors.len() as u64).to_le_bytes())?;
+
+        for (ids, distances) in self.neighbors.iter().zip(&self.distances) {
+            writer.write_all(&(ids.len() as u32).to_le_bytes())?;
+            for id in ids {
+                writer.write_all(&(*id as u64).to_le_bytes())?;
+            }
+            for dist in distances {
+                writer.write_all(&dist.to_le_bytes())?;
+            }
+        }
+
+        writer.flush()
+    }
+
+    pub fn load(path: &str) -> io::Result<(Self, Fingerprint)> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+            return Err(invalid_data("not a ground truth file"));
+        }
+
+        let mut metric = [0u8; 1];
+        reader.read_exact(&mut metric)?;
+        let fingerprint = Fingerprint {
+            metric: metric_from_code(metric[0])?,
+            dataset: read_u64(&mut reader)?,
+            queries: read_u64(&mut reader)?,
+        };
+
+        let num_queries = read_u64(&mut reader)? as usize;
+        let mut neighbors = Vec::with_capacity(num_queries);
+        let mut distances = Vec::with_capacity(num_queries);
+
+        for _ in 0..num_queries {
+            let len = read_u32(&mut reader)? as usize;
+            let ids = (0..len)
+                .map(|_| read_u64(&mut reader).map(|id| id as VectorID))
+                .collect::<io::Result<_>>()?;
+            let dists = (0..len)
+                .map(|_| read_u32(&mut reader).map(f32::from_bits))
+                .collect::<io::Result<_>>()?;
+            neighbors.push(ids);
+            distances.push(dists);
+        }
+
+        Ok((
+            Self {
+                neighbors,
+                distances,
+            },
+            fingerprint,
+        ))
+    }
+
+    /// Load neighbor ids from an `.ivecs` file, where every row is a
+    /// little-endian `i32` count followed by that many `i32` ids.
+    pub fn load_ivecs(path: &str) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut neighbors = Vec::new();
+
+        loop {
+            let len = match read_u32(&mut reader) {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let row = (0..len)
+                .map(|_| read_u32(&mut reader).map(|id| id as VectorID))
+                .collect::<io::Result<_>>()?;
+            neighbors.push(row);
+        }
+
+        Ok(Self {
+            neighbors,
+            distances: Vec::new(),
+        })
+    }
+
+    /// Save neighbor ids as `.ivecs`, readable by other ANN tooling.
+    pub fn save_ivecs(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for ids in &self.neighbors {
+            writer.write_all(&(ids.len() as u32).to_le_bytes())?;
+            for id in ids {
+                writer.write_all(&(*id as u32).to_le_bytes())?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Save neighbor distances as `.fvecs`, in the same layout as `.ivecs`.
+    pub fn save_fvecs(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for distances in &self.distances {
+            writer.write_all(&(distances.len() as u32).to_le_bytes())?;
+            for dist in distances {
+                writer.write_all(&dist.to_le_bytes())?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+fn metric_code(metric: Metric) -> u8 {
+    match metric {
+        Metric::SquaredEuclidean => 0,
+        Metric::Cosine => 1,
+        Metric::Euclidean => 2,
+        Metric::Angular => 3,
+        Metric::Manhattan => 4,
+    }
+}
+
+fn metric_from_code(code: u8) -> io::Result<Metric> {
+    match code {
+        0 => Ok(Metric::SquaredEuclidean),
+        1 => Ok(Metric::Cosine),
+        2 => Ok(Metric::Euclidean),
+        3 => Ok(Metric::Angular),
+        4 => Ok(Metric::Manhattan),
+        _ => Err(invalid_data("unknown metric")),
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let data: Vec<(VectorID, Vec<f32>)> = (0..20).map(|i| (i, vec![i as f32, 1.0])).collect();
+        let queries = vec![vec![2.2, 0.0], vec![15.1, 1.0]];
+        let path = env::temp_dir().join(format!("nn-groundtruth-{}.gt", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let computed =
+            GroundTruth::load_or_compute(path, &data, &queries, 4, Metric::SquaredEuclidean)
+                .unwrap();
+        assert_eq!(computed.neighbors[0], [2, 3, 1, 4]);
+
+        let (loaded, fingerprint) = GroundTruth::load(path).unwrap();
+        assert_eq!(loaded.neighbors, computed.neighbors);
+        assert_eq!(loaded.distances, computed.distances);
+        assert_eq!(
+            fingerprint,
+            Fingerprint::new(&data, &queries, Metric::SquaredEuclidean)
+        );
+        assert_ne!(
+            fingerprint,
+            Fingerprint::new(&data, &queries, Metric::Cosine)
+        );
+
+        let smaller =
+            GroundTruth::load_or_compute(path, &data, &queries, 2, Metric::SquaredEuclidean)
+                .unwrap();
+        assert_eq!(smaller.neighbors[1], [15, 16]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_export_roundtrip() {
+        let data: Vec<(VectorID, Vec<f32>)> = (0..20).map(|i| (i, vec![i as f32])).collect();
+        let truth = GroundTruth::compute(&data, &[vec![7.4]], 3, Metric::SquaredEuclidean);
+        let path = env::temp_dir().join(format!("nn-groundtruth-{}", std::process::id()));
+        let (ivecs, fvecs) = (
+            format!("{}.ivecs", path.display()),
+            format!("{}.fvecs", path.display()),
+        );
+
+        truth.save_ivecs(&ivecs).unwrap();
+        truth.save_fvecs(&fvecs).unwrap();
+        assert_eq!(
+            GroundTruth::load_ivecs(&ivecs).unwrap().neighbors,
+            [[7, 8, 6]]
+        );
+        assert_eq!(
+            crate::annbench::load_fvecs(&fvecs).unwrap(),
+            truth.distances
+        );
+
+        std::fs::remove_file(ivecs).unwrap();
+        std::fs::remove_file(fvecs).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{memory::MemoryUsage, Algorithm, VectorID};
+
+/// A search algorithm together with the labelled dataset it was built from,
+/// so that queries can be made by label and results reported by label.
+pub struct Index {
+    algorithm: Box<dyn Algorithm>,
+    name: String,
+    data: Vec<(String, Vec<f32>)>,
+    labels: HashMap<String, VectorID>,
+}
+
+/// A query is either a label from the dataset or a raw vector.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Query {
+    Label { label: String },
+    Vector { vector: Vec<f32> },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Neighbor<'a> {
+    pub id: VectorID,
+    pub label: &'a str,
+}
+
+impl Query {
+    /// Parse a line of a query file. A line with a single token is a label,
+    /// otherwise it is a whitespace separated vector.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            [] => Err("empty query".to_owned()),
+            [label] => Ok(Query::Label {
+                label: label.to_string(),
+            }),
+            values => values
+                .iter()
+                .map(|s| s.parse().map_err(|_| format!("invalid value {s:?}")))
+                .collect::<Result<_, _>>()
+                .map(|vector| Query::Vector { vector }),
+        }
+    }
+}
+
+impl Index {
+    pub fn new(algorithm: Box<dyn Algorithm>, name: &str, data: Vec<(String, Vec<f32>)>) -> Self {
+        let labels = data
+            .iter()
+            .enumerate()
+            .map(|(idx, (label, _vector))| (label.clone(), idx))
+            .collect();
+
+        Self {
+            algorithm,
+            name: name.to_owned(),
+            data,
+            labels,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.data.first().map_or(0, |(_label, vector)| vector.len())
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.algorithm.memory_usage()
+    }
+
+    /// Resolve a query to the vector it refers to.
+    pub fn resolve<'a>(&'a self, query: &'a Query) -> Result<&'a [f32], String> {
+        match query {
+            Query::Label { label } => self
+                .labels
+                .get(label)
+                .map(|&id| self.data[id].1.as_slice())
+                .ok_or_else(|| format!("label {label:?} not in dataset")),
+            Query::Vector { vector } if vector.len() != self.dimensions() => Err(format!(
+                "expected vector with {} dimensions, got {}",
+                self.dimensions(),
+                vector.len()
+            )),
+            Query::Vector { vector } => Ok(vector),
+        }
+    }
+
+    /// `k` is capped at the no. of vectors, since indexes allocate room for
+    /// `k` results up front.
+    pub fn search(&self, query: &Query, k: usize) -> Result<Vec<Neighbor<'_>>, String> {
+        let vector = self.resolve(query)?;
+        Ok(self
+            .algorithm
+            .search(vector, k.min(self.len()))
+            .into_iter()
+            .map(|id| Neighbor {
+                id,
+                label: &self.data[id].0,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_parse() {
+        assert!(matches!(Query::parse("king"), Ok(Query::Label { label }) if label == "king"));
+        assert!(matches!(
+            Query::parse("0.5 -1 2e3"),
+            Ok(Query::Vector { vector }) if vector == [0.5, -1.0, 2000.0]
+        ));
+        assert!(Query::parse("0.5 x").is_err());
+        assert!(Query::parse("  ").is_err());
+    }
+}
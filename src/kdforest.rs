@@ -0,0 +1,269 @@
+use std::{cmp::Reverse, collections::BinaryHeap, mem::size_of, sync::Mutex};
+
+use rand::Rng;
+
+use crate::{
+    distance,
+    kdtree::{build_nodes, TreeItem},
+    memory::{vec_bytes, MemoryUsage},
+    stats::{SearchStats, Stats},
+    Algorithm, BinaryTree, LimitedHeap, Node, OrdItem, VectorID, Vectors,
+};
+
+/// Randomized KD-trees over the same vectors, searched together best bin
+/// first until a budget of distance computations is spent (Silpa-Anan &
+/// Hartley, as in FLANN).
+pub struct KDForest {
+    trees: Vec<ForestTree>,
+    vectors: Vectors,
+    /// id of the vector in each row
+    ids: Vec<VectorID>,
+    max_checks: usize,
+    /// cleared bitsets of one bit per row, which searches take to mark the
+    /// rows they compared and put back cleared row by row, so that a search
+    /// costs no more than its budget however many vectors there are
+    checked: Mutex<Vec<Vec<u64>>>,
+}
+
+struct ForestTree {
+    nodes: BinaryTree<TreeItem>,
+    /// leaves hold ranges of this, which are rows of the shared vectors
+    rows: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KDForestConfig {
+    /// no. of randomized trees
+    pub trees: usize,
+    /// max no. of points in a leaf, which are scanned by brute force
+    pub leaf_size: usize,
+    /// every split is on a random one of the this many highest variance axes
+    pub top_axes: usize,
+    /// distance computations after which a search stops, once it has found
+    /// k neighbors. Searching more than the no. of vectors is exact.
+    pub max_checks: usize,
+}
+
+impl Default for KDForestConfig {
+    fn default() -> Self {
+        Self {
+            trees: 4,
+            leaf_size: 16,
+            top_axes: 5,
+            max_checks: 256,
+        }
+    }
+}
+
+impl Algorithm for KDForest {
+    fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
+        self.nearest_neighbors(query, k, &mut ())
+    }
+
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats) {
+        let mut stats = SearchStats::default();
+        (self.nearest_neighbors(query, k, &mut stats), stats)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            vectors: self.vectors.bytes() + vec_bytes(&self.ids),
+            nodes: vec_bytes(&self.trees)
+                + self
+                    .trees
+                    .iter()
+                    .map(|tree| {
+                        tree.nodes.nodes.capacity() * size_of::<Node<TreeItem>>()
+                            + vec_bytes(&tree.rows)
+                    })
+                    .sum::<usize>(),
+            scratch: self.checked.lock().unwrap().iter().map(vec_bytes).sum(),
+            ..Default::default()
+        }
+    }
+}
+
+impl KDForest {
+    pub fn load(data: &[(VectorID, Vec<f32>)]) -> Self {
+        Self::with_config(data, KDForestConfig::default())
+    }
+
+    pub fn with_config(data: &[(VectorID, Vec<f32>)], config: KDForestConfig) -> Self {
+        let dimensions = data.first().map_or(0, |(_, v)| v.len());
+        let mut vectors = Vectors::with_capacity(dimensions, data.len());
+        let mut ids = Vec::with_capacity(data.len());
+        for (id, vector) in data {
+            vectors.push(vector);
+            ids.push(*id);
+        }
+
+        let leaf_size = config.leaf_size.max(1);
+        let mut rng = rand::thread_rng();
+        let trees = (0..config.trees.max(1))
+            .map(|_| {
+                let mut tree = ForestTree {
+                    nodes: BinaryTree::with_capacity(2 * data.len() / leaf_size),
+                    rows: Vec::with_capacity(data.len()),
+                };
+                let mut points: Vec<u32> = (0..data.len() as u32).collect();
+                build_nodes(
+                    &mut tree.nodes,
+                    &mut points,
+                    &|row| vectors.row(*row as usize),
+                    leaf_size,
+                    &mut |variances| random_top_axis(variances, config.top_axes, &mut rng),
+                    &mut |points| {
+                        let start = tree.rows.len() as u32;
+                        tree.rows.extend_from_slice(points);
+                        start..tree.rows.len() as u32
+                    },
+                );
+                tree
+            })
+            .collect();
+
+        Self {
+            trees,
+            vectors,
+            ids,
+            max_checks: config.max_checks,
+            checked: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Changing the no. of checks doesn't require rebuilding the trees.
+    pub fn set_max_checks(&mut self, max_checks: usize) {
+        self.max_checks = max_checks;
+    }
+
+    fn nearest_neighbors(&self, target: &[f32], k: usize, stats: &mut impl Stats) -> Vec<VectorID> {
+        if k == 0 {
+            return vec![];
+        }
+
+        let mut checked = self
+            .checked
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0; self.vectors.len().div_ceil(64)]);
+
+        let mut search = Search {
+            target,
+            neighbors: LimitedHeap::new(k),
+            // branches not taken, by their distance from the target
+            branches: BinaryHeap::new(),
+            checked: &mut checked,
+            checked_rows: Vec::with_capacity(self.max_checks),
+        };
+
+        for tree in 0..self.trees.len() {
+            if let Some(root) = self.trees[tree].nodes.root() {
+                self.descend(&mut search, tree, root, 0.0, stats);
+            }
+        }
+
+        while let Some(Reverse(OrdItem(bound, (tree, node)))) = search.branches.pop() {
+            if search.neighbors.is_full()
+                && (search.checked_rows.len() >= self.max_checks
+                    || bound >= search.neighbors.peek().unwrap().0)
+            {
+                // none of the queued branches are searched
+                for _ in 0..=search.branches.len() {
+                    stats.prune();
+                }
+                break;
+            }
+            self.descend(&mut search, tree, node, bound, stats);
+        }
+
+        for row in &search.checked_rows {
+            search.checked[*row as usize / 64] = 0;
+        }
+        let neighbors = search.neighbors.consume().into_sorted_vec();
+        self.checked.lock().unwrap().push(checked);
+
+        neighbors
+            .into_iter()
+            .map(|OrdItem(_, row)| self.ids[row])
+            .collect()
+    }
+
+    /// Follow the closer child down to a leaf, queueing the other children
+    fn descend(
+        &self,
+        search: &mut Search,
+        tree: usize,
+        mut node: usize,
+        bound: f32,
+        stats: &mut impl Stats,
+    ) {
+        let ForestTree { nodes, rows } = &self.trees[tree];
+        loop {
+            stats.visit();
+            let n = &nodes[node];
+            match n.value {
+                TreeItem::Leaf { start, end } => {
+                    for &row in &rows[start as usize..end as usize] {
+                        let (word, bit) = (row as usize / 64, 1 << (row % 64));
+                        if search.checked[word] & bit != 0 {
+                            continue;
+                        }
+                        search.checked[word] |= bit;
+                        search.checked_rows.push(row);
+                        stats.distance();
+                        let row = row as usize;
+                        let dist = distance(search.target, self.vectors.row(row));
+                        search.neighbors.push(OrdItem(dist, row));
+                    }
+                    return;
+                }
+                TreeItem::Split { axis, value } => {
+                    let gap = search.target[axis as usize] - value;
+                    let (near, far) = if gap < 0.0 {
+                        (n.left(), n.right())
+                    } else {
+                        (n.right(), n.left())
+                    };
+
+                    if let Some(far) = far {
+                        // the target is at least this far from the other side
+                        let far_bound = bound.max(gap * gap);
+                        if search.neighbors.is_full()
+                            && far_bound >= search.neighbors.peek().unwrap().0
+                        {
+                            stats.prune();
+                        } else {
+                            search
+                                .branches
+                                .push(Reverse(OrdItem(far_bound, (tree, far))));
+                        }
+                    }
+
+                    match near {
+                        Some(near) => node = near,
+                        None => return,
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Search<'a> {
+    target: &'a [f32],
+    neighbors: LimitedHeap<OrdItem<usize>>,
+    branches: BinaryHeap<Reverse<OrdItem<(usize, usize)>>>,
+    /// rows already compared, as trees share them
+    checked: &'a mut [u64],
+    /// the rows set in `checked`, in the order they were compared
+    checked_rows: Vec<u32>,
+}
+
+/// A random one of the `top` highest variance axes
+fn random_top_axis(variances: &[f32], top: usize, rng: &mut impl Rng) -> usize {
+    let mut axes: Vec<usize> = (0..variances.len()).collect();
+    let top = top.clamp(1, axes.len());
+    axes.select_nth_unstable_by(top - 1, |a, b| variances[*b].total_cmp(&variances[*a]));
+    axes[rng.gen_range(0..top)]
+}
@@ -1,80 +1,293 @@
-use crate::{distance, Algorithm, BinaryTree, OrdItem, LimitedHeap, Node, VectorID};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    mem::size_of,
+    ops::Range,
+};
 
+use crate::{
+    distance,
+    memory::{hashmap_bytes, vec_bytes, MemoryUsage},
+    stats::{SearchStats, Stats},
+    Algorithm, BinaryTree, LimitedHeap, Node, OrdItem, VectorID, Vectors,
+};
+
+/// Points can be added and removed after the build with the logarithmic
+/// method: every inserted point starts as a tree of its own, and trees of
+/// about the same size are merged by rebuilding them as one. Removed points
+/// are only marked, until half of the points are, when all trees are
+/// rebuilt as one without them.
 pub struct KDTree {
-    tree: BinaryTree<TreeItem>,
+    /// from largest to smallest
+    trees: Vec<Tree>,
+    /// tree and row of every point, built on the first update so that a
+    /// tree that's never updated doesn't pay for it
+    locations: Option<HashMap<VectorID, (usize, u32)>>,
+    dimensions: usize,
+    leaf_size: usize,
+    epsilon: f32,
+}
+
+struct Tree {
+    nodes: BinaryTree<TreeItem>,
+    vectors: Vectors,
+    /// id of the vector in each row
+    ids: Vec<VectorID>,
+    /// rows that were removed
+    dead: Vec<bool>,
+    live: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KDTreeConfig {
+    /// max no. of points in a leaf, which are scanned by brute force
+    pub leaf_size: usize,
+    /// searches may stop early as long as every neighbor they return is
+    /// within (1 + epsilon) times the distance of the true one. 0 is exact.
+    pub epsilon: f32,
+}
+
+impl Default for KDTreeConfig {
+    fn default() -> Self {
+        Self {
+            leaf_size: 16,
+            epsilon: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum TreeItem {
+    /// points left of the split have a coordinate <= `value` on `axis`,
+    /// points right of it >=
+    Split {
+        axis: u32,
+        value: f32,
+    },
+    Leaf {
+        start: u32,
+        end: u32,
+    },
 }
 
+/// A subtree and the last change to the target's offsets from its region,
+/// an index into the search's changes
+type Branch = (usize, u32);
+
+/// The region of a queued subtree differs from that of the branch it was
+/// queued from along one axis only, so that's all it records. Following
+/// `previous` back to the root rebuilds its offsets along every axis.
+struct Change {
+    axis: u32,
+    offset: f32,
+    previous: u32,
+}
+
+/// `previous` of the changes at the root
+const ROOT: u32 = u32::MAX;
+
 impl Algorithm for KDTree {
     fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
-        self.nearest_neighbors(query, k)
+        self.nearest_neighbors(query, k, &mut ())
+    }
+
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats) {
+        let mut stats = SearchStats::default();
+        (self.nearest_neighbors(query, k, &mut stats), stats)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        let locations = self.locations.as_ref().map_or(0, hashmap_bytes);
+        MemoryUsage {
+            vectors: self
+                .trees
+                .iter()
+                .map(|tree| tree.vectors.bytes() + vec_bytes(&tree.ids))
+                .sum(),
+            nodes: vec_bytes(&self.trees)
+                + locations
+                + self
+                    .trees
+                    .iter()
+                    .map(|tree| {
+                        tree.nodes.nodes.capacity() * size_of::<Node<TreeItem>>()
+                            + vec_bytes(&tree.dead)
+                    })
+                    .sum::<usize>(),
+            ..Default::default()
+        }
     }
 }
 
 impl KDTree {
     pub fn load(data: &[(VectorID, Vec<f32>)]) -> Self {
-        let mut points: Vec<TreeItem> = data
-            .iter()
-            .map(|(id, vector)| TreeItem(vector.clone(), *id))
-            .collect();
+        Self::with_config(data, KDTreeConfig::default())
+    }
 
-        let num_dimensions = points[0].0.len();
+    /// An empty tree for points with `dimensions` coordinates, to be filled
+    /// with `insert`.
+    pub fn new(dimensions: usize, config: KDTreeConfig) -> Self {
         Self {
-            tree: Self::build(&mut points, 0, num_dimensions),
+            trees: Vec::new(),
+            locations: None,
+            dimensions,
+            leaf_size: config.leaf_size.max(1),
+            epsilon: config.epsilon,
         }
     }
 
-    fn build(points: &mut [TreeItem], depth: usize, num_dimensions: usize) -> BinaryTree<TreeItem> {
-        if points.is_empty() {
-            BinaryTree(None)
-        } else {
-            let axis = depth % num_dimensions;
-            points.sort_by(|a, b| a.0[axis].partial_cmp(&b.0[axis]).unwrap());
+    pub fn with_config(data: &[(VectorID, Vec<f32>)], config: KDTreeConfig) -> Self {
+        let Some((_, first)) = data.first() else {
+            return Self::new(0, config);
+        };
+        let mut s = Self::new(first.len(), config);
 
-            let median_idx = points.len() / 2;
+        let mut points: Vec<(VectorID, &[f32])> =
+            data.iter().map(|(id, v)| (*id, v.as_slice())).collect();
+        s.trees.push(s.build(&mut points));
+        s
+    }
 
-            let (left, right) = points.split_at_mut(median_idx);
-            let (value, right) = right.split_first_mut().unwrap();
+    /// Changing epsilon doesn't require rebuilding the tree.
+    pub fn set_epsilon(&mut self, epsilon: f32) {
+        self.epsilon = epsilon;
+    }
+
+    /// No. of points, not counting removed ones
+    pub fn len(&self) -> usize {
+        self.trees.iter().map(|tree| tree.live).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add a point, replacing any point with the same id.
+    pub fn insert(&mut self, id: VectorID, vector: &[f32]) {
+        assert_eq!(vector.len(), self.dimensions);
+        self.remove(id);
+
+        let tree = self.build(&mut [(id, vector)]);
+        self.trees.push(tree);
+        self.locate(self.trees.len() - 1);
 
-            BinaryTree(Some(Box::new(Node {
-                value: value.to_owned(),
-                left: Self::build(left, depth + 1, num_dimensions),
-                right: Self::build(right, depth + 1, num_dimensions),
-            })))
+        // merge trees while the newest is at least half the size of the one
+        // before it, which keeps the no. of trees logarithmic
+        while let [.., previous, last] = self.trees.as_slice() {
+            if previous.live > 2 * last.live {
+                break;
+            }
+            let last = self.trees.pop().unwrap();
+            let previous = self.trees.pop().unwrap();
+            let merged = self.rebuild(&[previous, last]);
+            self.trees.push(merged);
+            self.locate(self.trees.len() - 1);
         }
     }
 
-    fn nearest_neighbors(&self, target: &[f32], k: usize) -> Vec<VectorID> {
-        let num_dimensions = target.len();
+    /// Remove the point with the given id, returning whether there was one.
+    pub fn remove(&mut self, id: VectorID) -> bool {
+        let Some((tree, row)) = self.locations().remove(&id) else {
+            return false;
+        };
+        let tree = &mut self.trees[tree];
+        tree.dead[row as usize] = true;
+        tree.live -= 1;
 
-        let mut k_max_heap: LimitedHeap<OrdItem<VectorID>> = LimitedHeap::new(k);
-        let mut stack = vec![(&self.tree, 0)];
+        let rows: usize = self.trees.iter().map(|tree| tree.ids.len()).sum();
+        if 2 * self.len() < rows {
+            self.compact();
+        }
+        true
+    }
 
-        while let Some((node, depth)) = stack.pop() {
-            if let Some(point) = &node.0 {
-                let axis = depth % num_dimensions;
-                let dist = distance(target, &point.value.0);
+    /// Rebuild all trees as one without the removed points
+    fn compact(&mut self) {
+        let trees = std::mem::take(&mut self.trees);
+        let tree = self.rebuild(&trees);
+        if tree.live > 0 {
+            self.trees.push(tree);
+        }
+        if self.locations.is_some() {
+            self.locations = None;
+            self.locations();
+        }
+    }
 
-                k_max_heap.push(OrdItem(dist, point.value.1));
+    fn rebuild(&self, trees: &[Tree]) -> Tree {
+        let mut points: Vec<(VectorID, &[f32])> = trees
+            .iter()
+            .flat_map(|tree| {
+                (0..tree.ids.len())
+                    .filter(|row| !tree.dead[*row])
+                    .map(|row| (tree.ids[row], tree.vectors.row(row)))
+            })
+            .collect();
+        self.build(&mut points)
+    }
 
-                let next_branch;
-                let opposite_branch;
+    /// Rows are added leaf by leaf, so that a subtree's vectors are next to
+    /// each other in memory.
+    fn build(&self, points: &mut [(VectorID, &[f32])]) -> Tree {
+        let mut tree = Tree {
+            nodes: BinaryTree::with_capacity(2 * points.len() / self.leaf_size),
+            vectors: Vectors::with_capacity(self.dimensions, points.len()),
+            ids: Vec::with_capacity(points.len()),
+            dead: vec![false; points.len()],
+            live: points.len(),
+        };
 
-                if target[axis] < point.value.0[axis] {
-                    (next_branch, opposite_branch) = (&point.left, &point.right);
-                } else {
-                    (next_branch, opposite_branch) = (&point.right, &point.left);
+        build_nodes(
+            &mut tree.nodes,
+            points,
+            &|point| point.1,
+            self.leaf_size,
+            &mut |variances| argmax(variances),
+            &mut |points| {
+                let start = tree.vectors.len() as u32;
+                for (id, vector) in points {
+                    tree.vectors.push(vector);
+                    tree.ids.push(*id);
                 }
+                start..tree.vectors.len() as u32
+            },
+        );
+        tree
+    }
 
-                stack.push((next_branch, depth + 1));
+    fn locations(&mut self) -> &mut HashMap<VectorID, (usize, u32)> {
+        if self.locations.is_none() {
+            self.locations = Some(HashMap::with_capacity(self.len()));
+            for tree in 0..self.trees.len() {
+                self.locate(tree);
+            }
+        }
+        self.locations.as_mut().unwrap()
+    }
 
-                if (k_max_heap.len() < k)
-                    || (f32::powi(target[axis] - point.value.0[axis], 2)
-                        < k_max_heap.peek().unwrap().0)
-                {
-                    stack.push((opposite_branch, depth + 1));
-                }
+    /// Record where the live points of a tree are, if locations are tracked
+    fn locate(&mut self, index: usize) {
+        let Some(locations) = &mut self.locations else {
+            return;
+        };
+        let tree = &self.trees[index];
+        for (row, id) in tree.ids.iter().enumerate() {
+            if !tree.dead[row] {
+                locations.insert(*id, (index, row as u32));
             }
         }
+    }
+
+    fn nearest_neighbors(&self, target: &[f32], k: usize, stats: &mut impl Stats) -> Vec<VectorID> {
+        if k == 0 {
+            return vec![];
+        }
+
+        // neighbors are shared, so that those found in one tree prune the rest
+        let mut k_max_heap: LimitedHeap<OrdItem<VectorID>> = LimitedHeap::new(k);
+        for tree in &self.trees {
+            tree.search(target, self.epsilon, &mut k_max_heap, stats);
+        }
 
         let mut v: Vec<&OrdItem<VectorID>> = k_max_heap.iter().collect();
         v.sort();
@@ -82,5 +295,194 @@ impl KDTree {
     }
 }
 
-#[derive(Debug, Clone)]
-struct TreeItem(Vec<f32>, VectorID);
+impl Tree {
+    /// Best bin first: subtrees are searched in order of the distance from
+    /// the target to their region, which is tracked incrementally from the
+    /// target's offset to the region along each axis (Arya & Mount). Queued
+    /// subtrees only record the offset that changed, and the offsets of one
+    /// are rebuilt when it's searched.
+    fn search(
+        &self,
+        target: &[f32],
+        epsilon: f32,
+        k_max_heap: &mut LimitedHeap<OrdItem<VectorID>>,
+        stats: &mut impl Stats,
+    ) {
+        // distances are squared, so is the bound
+        let slack = (1.0 + epsilon).powi(2);
+
+        let mut queue: BinaryHeap<Reverse<OrdItem<Branch>>> = BinaryHeap::new();
+        if let Some(root) = self.nodes.root() {
+            queue.push(Reverse(OrdItem(0.0, (root, ROOT))));
+        }
+        let mut changes: Vec<Change> = Vec::new();
+        // offsets of the region being searched, and the changes they're made of
+        let mut offsets = vec![0.0; target.len()];
+        let mut applied: Vec<u32> = Vec::new();
+
+        while let Some(Reverse(OrdItem(rd, (mut node, last)))) = queue.pop() {
+            if k_max_heap.is_full() && rd * slack >= k_max_heap.peek().unwrap().0 {
+                // the rest of the queue is at least as far
+                for _ in 0..=queue.len() {
+                    stats.prune();
+                }
+                break;
+            }
+
+            for change in applied.drain(..) {
+                offsets[changes[change as usize].axis as usize] = 0.0;
+            }
+            let mut change = last;
+            while change != ROOT {
+                applied.push(change);
+                change = changes[change as usize].previous;
+            }
+            // oldest first, so that later changes along the same axis win
+            for &change in applied.iter().rev() {
+                let Change { axis, offset, .. } = changes[change as usize];
+                offsets[axis as usize] = offset;
+            }
+
+            // follow the closer child down to a leaf, queueing the others;
+            // the offsets of the closer child are the same
+            loop {
+                stats.visit();
+                let n = &self.nodes[node];
+
+                let (axis, value) = match n.value {
+                    TreeItem::Leaf { start, end } => {
+                        self.scan(start as usize..end as usize, target, k_max_heap, stats);
+                        break;
+                    }
+                    TreeItem::Split { axis, value } => (axis as usize, value),
+                };
+
+                let gap = target[axis] - value;
+                let (near, far) = if gap < 0.0 {
+                    (n.left(), n.right())
+                } else {
+                    (n.right(), n.left())
+                };
+
+                if let Some(far) = far {
+                    // only the offset along the split axis changes
+                    let far_rd = rd - offsets[axis] * offsets[axis] + gap * gap;
+                    if k_max_heap.is_full() && far_rd * slack >= k_max_heap.peek().unwrap().0 {
+                        stats.prune();
+                    } else {
+                        changes.push(Change {
+                            axis: axis as u32,
+                            offset: gap,
+                            previous: last,
+                        });
+                        let change = changes.len() as u32 - 1;
+                        queue.push(Reverse(OrdItem(far_rd, (far, change))));
+                    }
+                }
+
+                match near {
+                    Some(near) => node = near,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Brute force over the contiguous rows of a leaf
+    fn scan(
+        &self,
+        rows: Range<usize>,
+        target: &[f32],
+        k_max_heap: &mut LimitedHeap<OrdItem<VectorID>>,
+        stats: &mut impl Stats,
+    ) {
+        for row in rows {
+            if self.dead[row] {
+                continue;
+            }
+            stats.distance();
+            k_max_heap.push(OrdItem(
+                distance(target, self.vectors.row(row)),
+                self.ids[row],
+            ));
+        }
+    }
+}
+
+/// Add the nodes of a tree over `points` in pre-order and return the root.
+/// Every node splits its points at the median along the axis `split_axis`
+/// picks given the variance of each axis. `leaf` stores the points of a
+/// leaf and returns the rows it put them in.
+pub(crate) fn build_nodes<'v, P>(
+    tree: &mut BinaryTree<TreeItem>,
+    points: &mut [P],
+    vector: &impl Fn(&P) -> &'v [f32],
+    leaf_size: usize,
+    split_axis: &mut impl FnMut(&[f32]) -> usize,
+    leaf: &mut impl FnMut(&[P]) -> Range<u32>,
+) -> Option<usize> {
+    if points.is_empty() {
+        return None;
+    }
+
+    if points.len() <= leaf_size {
+        let Range { start, end } = leaf(points);
+        return Some(tree.push(TreeItem::Leaf { start, end }));
+    }
+
+    let axis = split_axis(&variances(points, vector));
+    let median_idx = points.len() / 2;
+    points.select_nth_unstable_by(median_idx, |a, b| {
+        vector(a)[axis].total_cmp(&vector(b)[axis])
+    });
+
+    let value = vector(&points[median_idx])[axis];
+    let node = tree.push(TreeItem::Split {
+        axis: axis as u32,
+        value,
+    });
+
+    let (left, right) = points.split_at_mut(median_idx);
+    let left = build_nodes(tree, left, vector, leaf_size, split_axis, leaf);
+    let right = build_nodes(tree, right, vector, leaf_size, split_axis, leaf);
+    tree.set_children(node, left, right);
+
+    Some(node)
+}
+
+/// Variance of every axis, estimated from an evenly spaced sample of the
+/// points
+fn variances<'v, P>(points: &[P], vector: &impl Fn(&P) -> &'v [f32]) -> Vec<f32> {
+    const SAMPLE_SIZE: usize = 128;
+
+    let sample: Vec<&[f32]> = points
+        .iter()
+        .step_by(points.len().div_ceil(SAMPLE_SIZE))
+        .map(vector)
+        .collect();
+    let n = sample.len() as f32;
+
+    let mut mean = vec![0.0; sample[0].len()];
+    for vector in &sample {
+        for (m, x) in mean.iter_mut().zip(vector.iter()) {
+            *m += x / n;
+        }
+    }
+
+    let mut variance = vec![0.0; mean.len()];
+    for vector in &sample {
+        for ((v, m), x) in variance.iter_mut().zip(&mean).zip(vector.iter()) {
+            *v += (x - m) * (x - m);
+        }
+    }
+    variance
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap()
+        .0
+}
@@ -4,27 +4,50 @@ use std::{
     fmt,
     fs::File,
     io::{self, BufRead, BufReader},
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Index},
+    str::FromStr,
 };
 
 use simsimd::SpatialSimilarity;
 
+pub mod annbench;
+pub mod batch;
+pub mod eval;
 pub mod exact;
+pub mod groundtruth;
+pub mod index;
+pub mod kdforest;
 pub mod kdtree;
 pub mod lsh;
+pub mod memory;
+pub mod mvptree;
 pub mod nsw;
+pub mod server;
+pub mod stats;
+pub mod sweep;
+pub mod synthetic;
 pub mod vptree;
 
 use exact::Exact;
+use kdforest::KDForest;
 use kdtree::KDTree;
 use lsh::LSH;
+use memory::MemoryUsage;
+use mvptree::MvpTree;
 use nsw::NSW;
+use stats::SearchStats;
 use vptree::VPTree;
 
 pub type VectorID = usize;
 
-pub trait Algorithm {
+pub trait Algorithm: Send + Sync {
     fn search(&self, query: &[f32], k: usize) -> Vec<VectorID>;
+
+    /// Same as `search`, also counting the work the search did
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats);
+
+    /// Estimated heap bytes held by the index
+    fn memory_usage(&self) -> MemoryUsage;
 }
 
 pub fn get_search_algorithm<'a>(
@@ -34,13 +57,40 @@ pub fn get_search_algorithm<'a>(
     match flag {
         "exact" => Box::new(Exact::load(data)),
         "kdtree" => Box::new(KDTree::load(data)),
+        "kdforest" => Box::new(KDForest::load(data)),
         "vptree" => Box::new(VPTree::load(data)),
+        "mvptree" => Box::new(MvpTree::load(data)),
         "lsh" => Box::new(LSH::load(data)),
         "nsw" => Box::new(NSW::load(data)),
         _ => Box::new(Exact::load(data)),
     }
 }
 
+/// Whether the algorithm `flag` can search by `metric`. KD-trees and NSW
+/// only search by squared L2.
+pub fn supports_metric(flag: &str, metric: Metric) -> bool {
+    metric == Metric::SquaredEuclidean || !matches!(flag, "kdtree" | "kdforest" | "nsw")
+}
+
+/// Same as `get_search_algorithm`, ranking by `metric`. Fails for the
+/// algorithms that don't support it.
+pub fn get_search_algorithm_with_metric<'a>(
+    flag: &str,
+    data: &[(VectorID, Vec<f32>)],
+    metric: Metric,
+) -> Result<Box<dyn Algorithm + 'a>, String> {
+    if !supports_metric(flag, metric) {
+        return Err(format!("{flag} only supports the l2 metric"));
+    }
+    Ok(match flag {
+        "vptree" => Box::new(VPTree::with_metric(data, metric, Default::default())),
+        "mvptree" => Box::new(MvpTree::with_metric(data, metric, Default::default())),
+        "lsh" => Box::new(LSH::with_metric(data, metric, Default::default())),
+        "kdtree" | "kdforest" | "nsw" => get_search_algorithm(flag, data),
+        _ => Box::new(Exact::with_metric(data, metric)),
+    })
+}
+
 pub fn load_dataset(path: &str) -> io::Result<Vec<(String, Vec<f32>)>> {
     let input = File::open(path)?;
     let reader = BufReader::new(input);
@@ -97,6 +147,11 @@ impl<T: Ord> LimitedHeap<T> {
         }
     }
 
+    /// Whether the heap holds as many elements as its limit
+    pub fn is_full(&self) -> bool {
+        self.heap.len() >= self.limit
+    }
+
     pub fn consume(self) -> BinaryHeap<T> {
         self.heap
     }
@@ -117,7 +172,7 @@ impl<T> DerefMut for LimitedHeap<T> {
 }
 
 #[derive(Clone, Copy)]
-pub struct OrdItem<T>(f32, T);
+pub struct OrdItem<T>(pub f32, pub T);
 
 impl<T: fmt::Debug> fmt::Debug for OrdItem<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -145,29 +200,128 @@ impl<T> Ord for OrdItem<T> {
     }
 }
 
+/// Binary tree whose nodes live in one contiguous `Vec`, with children
+/// referenced by index instead of boxed pointers. The root is node 0.
 #[derive(Debug)]
-pub struct BinaryTree<T>(pub Option<Box<Node<T>>>);
+pub struct BinaryTree<T> {
+    pub nodes: Vec<Node<T>>,
+}
 
 #[derive(Debug)]
 pub struct Node<T> {
     pub value: T,
-    pub left: BinaryTree<T>,
-    pub right: BinaryTree<T>,
+    left: u32,
+    right: u32,
+}
+
+/// Index of a missing child
+const NIL: u32 = u32::MAX;
+
+impl<T> Node<T> {
+    pub fn left(&self) -> Option<usize> {
+        (self.left != NIL).then_some(self.left as usize)
+    }
+
+    pub fn right(&self) -> Option<usize> {
+        (self.right != NIL).then_some(self.right as usize)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.left == NIL && self.right == NIL
+    }
 }
 
 impl<T> BinaryTree<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            nodes: Vec::with_capacity(capacity),
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.len() == 0
+        self.nodes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn root(&self) -> Option<usize> {
+        (!self.nodes.is_empty()).then_some(0)
+    }
+
+    /// Add a node without children and return its index
+    pub fn push(&mut self, value: T) -> usize {
+        assert!(self.nodes.len() < NIL as usize, "too many nodes");
+        self.nodes.push(Node {
+            value,
+            left: NIL,
+            right: NIL,
+        });
+        self.nodes.len() - 1
+    }
+
+    pub fn set_children(&mut self, node: usize, left: Option<usize>, right: Option<usize>) {
+        let index = |child: Option<usize>| child.map_or(NIL, |i| i as u32);
+        self.nodes[node].left = index(left);
+        self.nodes[node].right = index(right);
+    }
+
+    /// All values in the tree, in no particular order
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter().map(|node| &node.value)
+    }
+}
+
+impl<T> Index<usize> for BinaryTree<T> {
+    type Output = Node<T>;
+
+    fn index(&self, index: usize) -> &Node<T> {
+        &self.nodes[index]
+    }
+}
+
+/// Vectors of one dimension stored row after row in a single allocation.
+#[derive(Debug, Clone)]
+pub struct Vectors {
+    dimensions: usize,
+    data: Vec<f32>,
+}
+
+impl Vectors {
+    pub fn with_capacity(dimensions: usize, rows: usize) -> Self {
+        Self {
+            dimensions,
+            data: Vec::with_capacity(dimensions * rows),
+        }
+    }
+
+    /// Append a vector and return its row
+    pub fn push(&mut self, vector: &[f32]) -> usize {
+        assert_eq!(vector.len(), self.dimensions);
+        self.data.extend_from_slice(vector);
+        self.len() - 1
+    }
+
+    pub fn row(&self, row: usize) -> &[f32] {
+        &self.data[row * self.dimensions..(row + 1) * self.dimensions]
     }
 
     pub fn len(&self) -> usize {
-        Self::len_helper(self)
+        self.data.len() / self.dimensions.max(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 
-    fn len_helper(tree: &BinaryTree<T>) -> usize {
-        tree.0.as_ref().map_or(0, |node| {
-            1 + Self::len_helper(&node.left) + Self::len_helper(&node.right)
-        })
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    /// Heap bytes of the storage
+    pub fn bytes(&self) -> usize {
+        memory::vec_bytes(&self.data)
     }
 }
 
@@ -179,10 +333,79 @@ pub fn dot_product(x: &[f32], y: &[f32]) -> f32 {
     f32::dot(x, y).unwrap() as f32
 }
 
-pub fn cosine_similarity(x: &[f32], y: &[f32]) -> f32 {
+/// `1 - cosine_similarity`, so that smaller is closer like the other metrics.
+pub fn cosine_distance(x: &[f32], y: &[f32]) -> f32 {
     f32::cosine(x, y).unwrap() as f32
 }
 
+pub fn cosine_similarity(x: &[f32], y: &[f32]) -> f32 {
+    1.0 - cosine_distance(x, y)
+}
+
+/// Angle between the vectors divided by π, in [0, 1]. Unlike the cosine
+/// distance it satisfies the triangle inequality and ranks the same.
+pub fn angular_distance(x: &[f32], y: &[f32]) -> f32 {
+    cosine_similarity(x, y).clamp(-1.0, 1.0).acos() / std::f32::consts::PI
+}
+
+/// L1 distance
+pub fn manhattan_distance(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y).map(|(a, b)| (a - b).abs()).sum()
+}
+
+/// Distance function used to rank neighbors. Smaller is always closer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Squared L2 distance, used by all indexes except `Exact`
+    SquaredEuclidean,
+    Euclidean,
+    /// `1 - cosine_similarity`
+    Cosine,
+    /// angle between the vectors divided by π
+    Angular,
+    /// L1 distance
+    Manhattan,
+}
+
+impl Metric {
+    pub fn distance(&self, x: &[f32], y: &[f32]) -> f32 {
+        match self {
+            Metric::SquaredEuclidean => distance(x, y),
+            Metric::Euclidean => distance(x, y).sqrt(),
+            Metric::Cosine => cosine_distance(x, y),
+            Metric::Angular => angular_distance(x, y),
+            Metric::Manhattan => manhattan_distance(x, y),
+        }
+    }
+
+    /// A metric that satisfies the triangle inequality and ranks neighbors
+    /// the same as this one
+    pub fn true_metric(self) -> Metric {
+        match self {
+            Metric::SquaredEuclidean => Metric::Euclidean,
+            Metric::Cosine => Metric::Angular,
+            metric => metric,
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "l2" | "sqeuclidean" => Ok(Metric::SquaredEuclidean),
+            "euclidean" => Ok(Metric::Euclidean),
+            "cosine" => Ok(Metric::Cosine),
+            "angular" => Ok(Metric::Angular),
+            "l1" | "manhattan" => Ok(Metric::Manhattan),
+            _ => Err(format!(
+                "unknown metric {s:?}, expected one of: l2, euclidean, cosine, angular, l1"
+            )),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,88 +1,526 @@
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, HashMap},
+    collections::{BinaryHeap, HashMap, HashSet},
+    hash::Hash,
 };
 
 use rand::Rng;
 
-use crate::{dot_product, Algorithm, VectorID};
+use crate::{
+    dot_product,
+    memory::{hashmap_bytes, vec_bytes, MemoryUsage},
+    stats::{SearchStats, Stats},
+    Algorithm, LimitedHeap, Metric, OrdItem, VectorID, Vectors,
+};
 
-type Hash = Vec<u8>;
 type PlaneNorm = Vec<f32>;
+/// a table and the positions in its `Probes::bits` to flip in the query's
+/// hash
+type Perturbation = (usize, Vec<u16>);
 
+/// Independent hash tables, each with its own random hyperplanes. A query's
+/// candidates are the union of its buckets in every table, ranked by their
+/// true distance.
 pub struct LSH {
-    buckets: HashMap<Hash, Vec<VectorID>>,
+    tables: Tables,
+    vectors: Vectors,
+    /// id of the vector in each row
+    ids: Vec<VectorID>,
+    metric: Metric,
+    candidate_factor: usize,
+    probes: usize,
+}
+
+/// Tables keyed by the narrowest signature that holds all their bits, as
+/// most have few enough for an integer
+enum Tables {
+    Narrow(Vec<Table<u64>>),
+    Wide(Vec<Table<u128>>),
+    Words(Vec<Table<Words>>),
+}
+
+struct Table<S> {
+    /// rows of the vectors in each bucket
+    buckets: HashMap<S, Vec<u32>>,
     plane_norms: Vec<PlaneNorm>,
 }
 
-impl Algorithm for LSH {
-    fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
-        let hash = Self::hash(&self.plane_norms, query);
+/// One bit per hyperplane of a table, set if a vector is on its positive
+/// side
+trait Signature: Copy + Eq + Hash {
+    /// no. of hyperplanes it has room for
+    const BITS: usize;
 
-        let mut heap: BinaryHeap<Reverse<(usize, &Hash)>> = BinaryHeap::new();
+    fn zero() -> Self;
 
-        self.buckets
-            .keys()
-            .for_each(|k| heap.push(Reverse((Self::hamming_distance(&hash, k), k))));
+    fn flip(&mut self, bit: usize);
+}
 
-        let mut result: Vec<VectorID> = Vec::new();
-        while let Some(Reverse((_, hash))) = heap.pop() {
-            result.extend(self.buckets.get(hash).unwrap().iter());
-            if result.len() >= k {
-                break;
-            }
+impl Signature for u64 {
+    const BITS: usize = 64;
+
+    fn zero() -> Self {
+        0
+    }
+
+    fn flip(&mut self, bit: usize) {
+        *self ^= 1 << bit;
+    }
+}
+
+impl Signature for u128 {
+    const BITS: usize = 128;
+
+    fn zero() -> Self {
+        0
+    }
+
+    fn flip(&mut self, bit: usize) {
+        *self ^= 1 << bit;
+    }
+}
+
+/// Signatures wider than 128 bits, up to 512
+type Words = [u64; 8];
+
+impl Signature for Words {
+    const BITS: usize = 64 * 8;
+
+    fn zero() -> Self {
+        [0; 8]
+    }
+
+    fn flip(&mut self, bit: usize) {
+        self[bit / 64] ^= 1 << (bit % 64);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LSHConfig {
+    /// no. of hash tables. More tables find more of the true neighbors.
+    pub tables: usize,
+    /// no. of random hyperplanes per table, max possible buckets = 2^bits.
+    /// More bits make buckets smaller and their points closer. At most 512,
+    /// and up to 64 make the smallest and fastest keys.
+    pub bits_per_table: usize,
+    /// buckets past the query's own are probed until there are at least
+    /// k * candidate_factor candidates, whose distances are then computed
+    /// to find the k closest
+    pub candidate_factor: usize,
+    /// no. of buckets probed per table on average, counting the query's
+    /// own. The budget of tables * probes is shared by all tables, whose
+    /// buckets are probed likeliest first, so one table may use more than
+    /// others. Searches return fewer than k neighbors if the probed buckets
+    /// hold fewer than k vectors.
+    pub probes: usize,
+}
+
+impl Default for LSHConfig {
+    fn default() -> Self {
+        Self {
+            tables: 8,
+            bits_per_table: 12,
+            candidate_factor: 10,
+            probes: 32,
         }
+    }
+}
+
+impl Algorithm for LSH {
+    fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
+        self.search_buckets(query, k, &mut ())
+    }
+
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats) {
+        let mut stats = SearchStats::default();
+        (self.search_buckets(query, k, &mut stats), stats)
+    }
 
-        result.truncate(k);
-        result
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            vectors: self.vectors.bytes() + vec_bytes(&self.ids),
+            ..Default::default()
+        };
+        match &self.tables {
+            Tables::Narrow(tables) => Self::table_usage(tables, &mut usage),
+            Tables::Wide(tables) => Self::table_usage(tables, &mut usage),
+            Tables::Words(tables) => Self::table_usage(tables, &mut usage),
+        }
+        usage
     }
 }
 
 impl LSH {
     pub fn load(data: &[(VectorID, Vec<f32>)]) -> Self {
-        let dimensionality = data.first().unwrap().1.len();
-        let num_hyperplanes = 18; // max possible buckets = 2^num_hyperplanes
+        Self::with_config(data, LSHConfig::default())
+    }
 
-        // norms of random hyperplanes
-        let plane_norms: Vec<Vec<f32>> = (0..num_hyperplanes)
-            .map(|_| Self::generate_plane_norm(dimensionality))
-            .collect();
+    pub fn with_config(data: &[(VectorID, Vec<f32>)], config: LSHConfig) -> Self {
+        Self::with_metric(data, Metric::SquaredEuclidean, config)
+    }
 
-        let mut buckets: HashMap<Hash, Vec<VectorID>> = HashMap::new();
+    /// Candidates are ranked by `metric`. The hyperplanes go through the
+    /// origin, so buckets group vectors by angle whatever the metric.
+    pub fn with_metric(data: &[(VectorID, Vec<f32>)], metric: Metric, config: LSHConfig) -> Self {
+        let dimensionality = data.first().map_or(0, |(_, v)| v.len());
 
-        for (key, vec) in data {
-            let hash = Self::hash(&plane_norms, vec);
-            if let Some(entry) = buckets.get_mut(&hash) {
-                entry.push(key.to_owned());
-            } else {
-                buckets.insert(hash, vec![key.to_owned()]);
-            }
+        let mut vectors = Vectors::with_capacity(dimensionality, data.len());
+        let mut ids = Vec::with_capacity(data.len());
+        for (id, vector) in data {
+            vectors.push(vector);
+            ids.push(*id);
         }
 
+        let config = LSHConfig {
+            bits_per_table: config.bits_per_table.min(Words::BITS),
+            ..config
+        };
+        let tables = if config.bits_per_table <= <u64 as Signature>::BITS {
+            Tables::Narrow(Self::build_tables(data, dimensionality, config))
+        } else if config.bits_per_table <= <u128 as Signature>::BITS {
+            Tables::Wide(Self::build_tables(data, dimensionality, config))
+        } else {
+            Tables::Words(Self::build_tables(data, dimensionality, config))
+        };
+
         Self {
-            buckets,
-            plane_norms,
+            tables,
+            vectors,
+            ids,
+            metric,
+            candidate_factor: config.candidate_factor,
+            probes: config.probes,
+        }
+    }
+
+    fn build_tables<S: Signature>(
+        data: &[(VectorID, Vec<f32>)],
+        dimensionality: usize,
+        config: LSHConfig,
+    ) -> Vec<Table<S>> {
+        (0..config.tables.max(1))
+            .map(|_| {
+                // norms of random hyperplanes
+                let plane_norms: Vec<Vec<f32>> = (0..config.bits_per_table)
+                    .map(|_| Self::generate_plane_norm(dimensionality))
+                    .collect();
+
+                let mut buckets: HashMap<S, Vec<u32>> = HashMap::new();
+                for (row, (_, vec)) in data.iter().enumerate() {
+                    let hash = Self::hash(&plane_norms, vec);
+                    buckets.entry(hash).or_default().push(row as u32);
+                }
+
+                Table {
+                    buckets,
+                    plane_norms,
+                }
+            })
+            .collect()
+    }
+
+    fn table_usage<S>(tables: &Vec<Table<S>>, usage: &mut MemoryUsage) {
+        usage.buckets += vec_bytes(tables);
+        for table in tables {
+            usage.buckets += hashmap_bytes(&table.buckets)
+                + table.buckets.values().map(vec_bytes).sum::<usize>();
+            usage.plane_norms += vec_bytes(&table.plane_norms)
+                + table.plane_norms.iter().map(vec_bytes).sum::<usize>();
         }
     }
 }
 
 impl LSH {
-    fn hash(hashers: &[PlaneNorm], v: &[f32]) -> Hash {
-        hashers
-            .iter()
-            .map(|norm| if dot_product(v, norm) >= 0.0 { 1 } else { 0 })
+    /// Changing the candidate pool doesn't require rehashing.
+    pub fn set_candidate_factor(&mut self, candidate_factor: usize) {
+        self.candidate_factor = candidate_factor;
+    }
+
+    /// Changing the probe budget doesn't require rehashing.
+    pub fn set_probes(&mut self, probes: usize) {
+        self.probes = probes;
+    }
+
+    /// Takes the union of the query's buckets in every table, then probes
+    /// nearby buckets of all tables most likely first (Lv et al.) until
+    /// there are enough candidates or the probe budget is spent, and
+    /// returns the k closest. The query's own buckets are always probed.
+    fn search_buckets(&self, query: &[f32], k: usize, stats: &mut impl Stats) -> Vec<VectorID> {
+        // without vectors, the hyperplanes have no dimensions to hash the query
+        if k == 0 || self.vectors.is_empty() {
+            return vec![];
+        }
+        let candidates = match &self.tables {
+            Tables::Narrow(tables) => self.candidates(tables, query, k, stats),
+            Tables::Wide(tables) => self.candidates(tables, query, k, stats),
+            Tables::Words(tables) => self.candidates(tables, query, k, stats),
+        };
+
+        let mut neighbors: LimitedHeap<OrdItem<u32>> = LimitedHeap::new(k);
+        for row in candidates.rows {
+            stats.distance();
+            let distance = self.metric.distance(query, self.vectors.row(row as usize));
+            neighbors.push(OrdItem(distance, row));
+        }
+
+        neighbors
+            .consume()
+            .into_sorted_vec()
+            .into_iter()
+            .map(|OrdItem(_, row)| self.ids[row as usize])
             .collect()
     }
 
+    /// Rows in the query's buckets and the nearby buckets probed after them
+    fn candidates<S: Signature>(
+        &self,
+        tables: &[Table<S>],
+        query: &[f32],
+        k: usize,
+        stats: &mut impl Stats,
+    ) -> Candidates {
+        let pool = k.saturating_mul(self.candidate_factor.max(1));
+        let budget = tables.len() * self.probes.max(1);
+
+        let probes: Vec<Probes<S>> = tables
+            .iter()
+            .map(|table| Probes::new(&table.plane_norms, query))
+            .collect();
+
+        // perturbations of every table's hash, by how far the query is from
+        // the hyperplanes whose side they flip
+        let mut heap: BinaryHeap<Reverse<OrdItem<Perturbation>>> = BinaryHeap::new();
+        let mut candidates = Candidates::default();
+
+        // the query's own buckets, whatever the budget
+        for (table, probes) in probes.iter().enumerate() {
+            stats.probe();
+            if let Some(bucket) = tables[table].buckets.get(&probes.hash) {
+                candidates.extend(bucket);
+            }
+            probes.push_next(table, 0.0, Vec::new(), &mut heap);
+        }
+
+        let mut probed = tables.len();
+        while candidates.rows.len() < pool && probed < budget {
+            let Some(Reverse(OrdItem(score, (table, flips)))) = heap.pop() else {
+                break;
+            };
+
+            let key = probes[table].key(&flips);
+            probed += 1;
+            stats.probe();
+            if let Some(bucket) = tables[table].buckets.get(&key) {
+                candidates.extend(bucket);
+            }
+
+            probes[table].push_next(table, score, flips, &mut heap);
+        }
+
+        candidates
+    }
+
+    fn hash<S: Signature>(hashers: &[PlaneNorm], v: &[f32]) -> S {
+        Self::pack(hashers.iter().map(|norm| dot_product(v, norm)))
+    }
+
+    /// Bit i is set if the vector is on the positive side of hyperplane i
+    fn pack<S: Signature>(dots: impl Iterator<Item = f32>) -> S {
+        let mut hash = S::zero();
+        for (i, dot) in dots.enumerate() {
+            if dot >= 0.0 {
+                hash.flip(i);
+            }
+        }
+        hash
+    }
+
+    /// Unit length, so that the dot product with a vector is its distance
+    /// from the hyperplane.
     fn generate_plane_norm(dimensionality: usize) -> PlaneNorm {
         let mut rng = rand::thread_rng();
-        (0..dimensionality)
+        let norm: Vec<f32> = (0..dimensionality)
             .map(|_| rng.gen_range(-0.5..=0.5))
-            .collect()
+            .collect();
+        let length = dot_product(&norm, &norm).sqrt().max(f32::MIN_POSITIVE);
+        norm.into_iter().map(|x| x / length).collect()
+    }
+}
+
+/// Candidate rows, each once even if it's in the buckets of several tables
+#[derive(Default)]
+struct Candidates {
+    rows: Vec<u32>,
+    seen: HashSet<u32>,
+}
+
+impl Candidates {
+    fn extend(&mut self, bucket: &[u32]) {
+        for row in bucket {
+            if self.seen.insert(*row) {
+                self.rows.push(*row);
+            }
+        }
+    }
+}
+
+/// The query's hash in one table and its bits ordered by how close the
+/// query is to their hyperplane, as the buckets across the closest
+/// hyperplanes are the likeliest to hold its neighbors.
+struct Probes<S> {
+    hash: S,
+    /// bits by increasing margin
+    bits: Vec<usize>,
+    /// distance from the query to the hyperplane of each of `bits`
+    margins: Vec<f32>,
+}
+
+impl<S: Signature> Probes<S> {
+    fn new(plane_norms: &[PlaneNorm], query: &[f32]) -> Self {
+        let dots: Vec<f32> = plane_norms
+            .iter()
+            .map(|norm| dot_product(query, norm))
+            .collect();
+        let hash = LSH::pack(dots.iter().copied());
+
+        let mut bits: Vec<usize> = (0..dots.len()).collect();
+        bits.sort_by(|a, b| dots[*a].abs().total_cmp(&dots[*b].abs()));
+        let margins = bits.iter().map(|bit| dots[*bit].abs()).collect();
+
+        Self {
+            hash,
+            bits,
+            margins,
+        }
+    }
+
+    /// The hash with the bits at the given positions of `bits` flipped
+    fn key(&self, flips: &[u16]) -> S {
+        let mut key = self.hash;
+        for flip in flips {
+            key.flip(self.bits[*flip as usize]);
+        }
+        key
+    }
+
+    /// Queue the perturbations that follow `flips`, shifting its last flip
+    /// to the next bit or also flipping the next bit. Starting from no
+    /// flips, this generates every set of flips once, by increasing score.
+    fn push_next(
+        &self,
+        table: usize,
+        score: f32,
+        flips: Vec<u16>,
+        heap: &mut BinaryHeap<Reverse<OrdItem<Perturbation>>>,
+    ) {
+        let next = flips.last().map_or(0, |last| *last as usize + 1);
+        if next >= self.bits.len() {
+            return;
+        }
+
+        if let Some(&last) = flips.last() {
+            let mut shifted = flips.clone();
+            *shifted.last_mut().unwrap() = next as u16;
+            let score = score - self.margins[last as usize] + self.margins[next];
+            heap.push(Reverse(OrdItem(score, (table, shifted))));
+        }
+
+        let mut expanded = flips;
+        expanded.push(next as u16);
+        heap.push(Reverse(OrdItem(
+            score + self.margins[next],
+            (table, expanded),
+        )));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probes_cover_every_bucket_by_score() {
+        let plane_norms: Vec<PlaneNorm> = (0..6).map(|_| LSH::generate_plane_norm(4)).collect();
+        let probes = Probes::<u64>::new(&plane_norms, &[0.3, -0.2, 0.9, 0.1]);
+
+        let mut heap = BinaryHeap::from([Reverse(OrdItem(0.0, (0, Vec::new())))]);
+        let mut keys = HashSet::new();
+        let mut last = 0.0;
+        while let Some(Reverse(OrdItem(score, (table, flips)))) = heap.pop() {
+            assert!(score >= last - 1e-6, "{score} after {last}");
+            last = score;
+            assert!(keys.insert(probes.key(&flips)), "{flips:?} probed twice");
+            probes.push_next(table, score, flips, &mut heap);
+        }
+        assert_eq!(keys.len(), 1 << 6);
+    }
+
+    #[test]
+    fn test_own_buckets_come_first() {
+        let data = crate::synthetic::uniform(500, 8, 1);
+        let config = LSHConfig {
+            tables: 4,
+            probes: 1,
+            ..LSHConfig::default()
+        };
+        let lsh = LSH::with_config(&data, config);
+        for (id, vector) in data.iter().step_by(50) {
+            let (result, stats) = lsh.search_with_stats(vector, 1);
+            assert_eq!(result, [*id]);
+            assert_eq!(stats.buckets_probed, 4);
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        let lsh = LSH::load(&[]);
+        assert!(lsh.search(&[0.5, 0.5], 3).is_empty());
+        assert_eq!(lsh.search_with_stats(&[0.5, 0.5], 3).1.buckets_probed, 0);
     }
 
-    fn hamming_distance(v1: &[u8], v2: &[u8]) -> usize {
-        assert_eq!(v1.len(), v2.len());
-        v1.iter().zip(v2.iter()).filter(|(a, b)| a != b).count()
+    #[test]
+    fn test_narrowest_signature() {
+        let data = crate::synthetic::uniform(50, 4, 1);
+        let tables = |bits_per_table| {
+            let config = LSHConfig {
+                bits_per_table,
+                ..LSHConfig::default()
+            };
+            LSH::with_config(&data, config).tables
+        };
+        assert!(matches!(tables(64), Tables::Narrow(_)));
+        assert!(matches!(tables(65), Tables::Wide(_)));
+        assert!(matches!(tables(128), Tables::Wide(_)));
+        assert!(matches!(tables(129), Tables::Words(_)));
+        let Tables::Words(words) = tables(1000) else {
+            panic!("1000 bits don't fit in an integer");
+        };
+        assert_eq!(words[0].plane_norms.len(), Words::BITS);
+    }
+
+    #[test]
+    fn test_hundreds_of_bits() {
+        let data = crate::synthetic::uniform(200, 8, 1);
+        let config = LSHConfig {
+            tables: 1,
+            bits_per_table: 300,
+            ..LSHConfig::default()
+        };
+        let lsh = LSH::with_config(&data, config);
+        for (id, vector) in data.iter().step_by(20) {
+            assert_eq!(lsh.search(vector, 1), [*id]);
+        }
+
+        // flipping a bit in the fifth word changes only that bit
+        let Tables::Words(tables) = &lsh.tables else {
+            panic!("300 bits don't fit in an integer");
+        };
+        let probes = Probes::<Words>::new(&tables[0].plane_norms, &data[0].1);
+        let flip = probes.bits.iter().position(|bit| *bit >= 256).unwrap();
+        let key = probes.key(&[flip as u16]);
+        let changed: Vec<u64> = key.iter().zip(&probes.hash).map(|(a, b)| a ^ b).collect();
+        assert_eq!(changed[..4], [0; 4]);
+        assert_eq!(changed[4], 1 << (probes.bits[flip] - 256));
+        assert_eq!(changed[5..], [0; 3]);
     }
 }
@@ -1,11 +1,23 @@
 use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::time::Instant;
 
 use argh::FromArgs;
 
-use nearest_neighbors::get_search_algorithm;
+use nearest_neighbors::annbench::{AnnDataset, AnnRunner};
+use nearest_neighbors::batch;
+use nearest_neighbors::eval;
+use nearest_neighbors::groundtruth::GroundTruth;
+use nearest_neighbors::index::{Index, Query};
 use nearest_neighbors::load_dataset;
-use nearest_neighbors::VectorID;
+use nearest_neighbors::server;
+use nearest_neighbors::sweep::{self, Grid, Sweep};
+use nearest_neighbors::{get_search_algorithm, get_search_algorithm_with_metric};
+use nearest_neighbors::{Metric, VectorID};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
 #[derive(FromArgs)]
 /// Configuration
@@ -14,34 +26,215 @@ struct Config {
     #[argh(option, short = 'a', default = "String::from(\"exact\")")]
     algorithm: String,
 
-    /// dataset path
-    #[argh(
-        option,
-        default = "env::var(\"DATASET_PATH\").expect(\"env DATASET_PATH should be set if path not provided\")"
-    )]
-    path: String,
+    /// dataset path (default: env DATASET_PATH)
+    #[argh(option)]
+    path: Option<String>,
 
     /// query
     #[argh(option, short = 'q')]
-    query: String,
+    query: Option<String>,
+
+    #[argh(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Mode {
+    Serve(ServeConfig),
+    Batch(BatchConfig),
+    Eval(EvalConfig),
+    Ann(AnnConfig),
+}
+
+#[derive(FromArgs)]
+/// Serve the index over HTTP
+#[argh(subcommand, name = "serve")]
+struct ServeConfig {
+    /// address to bind (default: 127.0.0.1:8080)
+    #[argh(option, default = "String::from(\"127.0.0.1:8080\")")]
+    addr: String,
+
+    /// number of worker threads (default: 4)
+    #[argh(option, default = "4")]
+    workers: usize,
+}
+
+#[derive(FromArgs)]
+/// Run every query in a file and write one result line per query
+#[argh(subcommand, name = "batch")]
+struct BatchConfig {
+    /// file with one label or space separated vector per line
+    #[argh(option, short = 'i')]
+    input: String,
+
+    /// file to write neighbor labels to, one line per query
+    #[argh(option, short = 'o')]
+    output: String,
+
+    /// no. of neighbors per query (default: 5)
+    #[argh(option, short = 'k', default = "5")]
+    k: usize,
+}
+
+#[derive(FromArgs)]
+/// Measure recall@k, MRR and distance ratio against exact ground truth
+#[argh(subcommand, name = "eval")]
+struct EvalConfig {
+    /// no. of neighbors per query (default: 10)
+    #[argh(option, short = 'k', default = "10")]
+    k: usize,
+
+    /// query file with one label or vector per line (default: sample the dataset)
+    #[argh(option)]
+    queries: Option<String>,
+
+    /// no. of queries to sample from the dataset (default: 1000)
+    #[argh(option, default = "1000")]
+    num_queries: usize,
+
+    /// seed used for sampling queries (default: 0)
+    #[argh(option, default = "0")]
+    seed: u64,
+
+    /// ground truth neighbor ids as .ivecs (default: computed with exact search)
+    #[argh(option)]
+    ground_truth: Option<String>,
+
+    /// metric of the ground truth: l2, euclidean, cosine, angular or l1 (default: l2)
+    #[argh(option, default = "Metric::SquaredEuclidean")]
+    metric: Metric,
+
+    /// where computed ground truth is cached (default: <dataset path>.gt)
+    #[argh(option)]
+    cache: Option<String>,
+
+    /// always recompute the ground truth
+    #[argh(switch)]
+    no_cache: bool,
+
+    /// write the ground truth to <export>.ivecs (ids) and <export>.fvecs
+    /// (distances) for other ANN tooling
+    #[argh(option)]
+    export: Option<String>,
+
+    /// sweep the parameters of every algorithm instead of evaluating one
+    #[argh(switch)]
+    sweep: bool,
+
+    /// JSON file with the parameter grid for --sweep (default: built-in grid)
+    #[argh(option)]
+    grid: Option<String>,
+
+    /// write sweep results as CSV
+    #[argh(option)]
+    csv: Option<String>,
+
+    /// write sweep results as JSON
+    #[argh(option)]
+    json: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Run every configuration of a grid the way ann-benchmarks does and write
+/// its result files
+#[argh(subcommand, name = "ann")]
+struct AnnConfig {
+    /// training vectors, .fvecs or the text dataset format
+    #[argh(option)]
+    train: String,
+
+    /// test queries, .fvecs or the text dataset format
+    #[argh(option)]
+    test: String,
+
+    /// true neighbors of the test queries as .ivecs
+    #[argh(option)]
+    neighbors: String,
+
+    /// dataset name used in the results (default: file name of --train)
+    #[argh(option)]
+    name: Option<String>,
+
+    /// no. of neighbors per query (default: 10)
+    #[argh(option, short = 'k', default = "10")]
+    k: usize,
+
+    /// metric: l2, euclidean, cosine, angular or l1 (default: l2)
+    #[argh(option, default = "Metric::SquaredEuclidean")]
+    metric: Metric,
+
+    /// times the query set is searched, keeping the fastest (default: 3)
+    #[argh(option, default = "3")]
+    runs: usize,
+
+    /// JSON file with the parameter grid (default: built-in grid)
+    #[argh(option)]
+    grid: Option<String>,
+
+    /// directory to write results to (default: results)
+    #[argh(option, short = 'o', default = "String::from(\"results\")")]
+    output: String,
 }
 
 fn main() {
     let config: Config = argh::from_env();
 
-    let data = load_dataset(&config.path).unwrap();
+    if let Some(Mode::Ann(ann_config)) = &config.mode {
+        run_ann(ann_config);
+        return;
+    }
+
+    let path = config.path.clone().unwrap_or_else(|| {
+        env::var("DATASET_PATH").expect("env DATASET_PATH should be set if path not provided")
+    });
+    let data = load_dataset(&path).unwrap();
     let formatted_data: Vec<(VectorID, Vec<f32>)> = data
         .clone()
         .iter()
         .enumerate()
         .map(|(idx, (_str, vector))| (idx, vector.clone()))
         .collect();
-    let hashmap: HashMap<String, Vec<f32>> = data.clone().into_iter().collect();
     println!("Loaded dataset. Found {} vectors.", formatted_data.len());
 
+    match config.mode {
+        Some(Mode::Serve(serve_config)) => {
+            let algorithm = get_search_algorithm(&config.algorithm, &formatted_data);
+            let index = Index::new(algorithm, &config.algorithm, data);
+            println!("Listening on http://{}", serve_config.addr);
+            server::serve(&index, &serve_config.addr, serve_config.workers).unwrap();
+            return;
+        }
+        Some(Mode::Batch(batch_config)) => {
+            let algorithm = get_search_algorithm(&config.algorithm, &formatted_data);
+            let index = Index::new(algorithm, &config.algorithm, data);
+            run_batch(&index, &batch_config);
+            return;
+        }
+        Some(Mode::Eval(eval_config)) => {
+            let cache = eval_config
+                .cache
+                .clone()
+                .unwrap_or_else(|| format!("{}.gt", path));
+            run_eval(
+                &config.algorithm,
+                &data,
+                &formatted_data,
+                &eval_config,
+                &cache,
+            );
+            return;
+        }
+        Some(Mode::Ann(_)) | None => {}
+    }
+
     let algorithm = get_search_algorithm(&config.algorithm, &formatted_data);
+    let query = config
+        .query
+        .expect("query is required in single-query mode");
+    let hashmap: HashMap<String, Vec<f32>> = data.clone().into_iter().collect();
     let query_vector = hashmap
-        .get(&config.query)
+        .get(&query)
         .expect("query key not in dataset, can't resolve key to vector");
     let result_ids = algorithm.search(query_vector, 5);
     let results: Vec<String> = result_ids
@@ -50,3 +243,190 @@ fn main() {
         .collect();
     println!("Results: {:?}", results);
 }
+
+fn run_batch(index: &Index, config: &BatchConfig) {
+    let queries = batch::read_queries(&config.input).unwrap();
+    let total = queries.len();
+    println!("Loaded {} queries.", total);
+
+    let step = (total / 100).max(1);
+    let start = Instant::now();
+    let results = batch::search_all(index, &queries, config.k, |done| {
+        if done % step == 0 || done == total {
+            eprint!("\rSearched {}/{} queries", done, total);
+        }
+    });
+    let elapsed = start.elapsed();
+    eprintln!();
+
+    batch::write_results(&config.output, &results).unwrap();
+
+    for (line_no, result) in results.iter().enumerate() {
+        if let Err(e) = result {
+            eprintln!("query {} failed: {}", line_no + 1, e);
+        }
+    }
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    println!(
+        "Searched {} queries ({} failed) in {:.2?}, {:.1} queries/s. Wrote results to {}.",
+        total,
+        failed,
+        elapsed,
+        total as f64 / elapsed.as_secs_f64(),
+        config.output
+    );
+}
+
+fn run_eval(
+    algorithm: &str,
+    labelled_data: &[(String, Vec<f32>)],
+    data: &[(VectorID, Vec<f32>)],
+    config: &EvalConfig,
+    cache: &str,
+) {
+    let queries: Vec<Vec<f32>> = match &config.queries {
+        Some(path) => {
+            let labels: HashMap<&str, &[f32]> = labelled_data
+                .iter()
+                .map(|(label, vector)| (label.as_str(), vector.as_slice()))
+                .collect();
+            batch::read_queries(path)
+                .unwrap()
+                .into_iter()
+                .enumerate()
+                .map(|(line_no, query)| {
+                    query.unwrap_or_else(|e| panic!("query {} is invalid: {}", line_no + 1, e))
+                })
+                .map(|query| match query {
+                    Query::Label { label } => labels
+                        .get(label.as_str())
+                        .expect("query label not in dataset")
+                        .to_vec(),
+                    Query::Vector { vector } => vector,
+                })
+                .collect()
+        }
+        None => data
+            .choose_multiple(&mut StdRng::seed_from_u64(config.seed), config.num_queries)
+            .map(|(_id, vector)| vector.clone())
+            .collect(),
+    };
+
+    let ground_truth = match &config.ground_truth {
+        Some(path) => GroundTruth::load_ivecs(path).unwrap(),
+        None if config.no_cache => GroundTruth::compute(data, &queries, config.k, config.metric),
+        None => {
+            let start = Instant::now();
+            let ground_truth =
+                GroundTruth::load_or_compute(cache, data, &queries, config.k, config.metric)
+                    .unwrap_or_else(|e| {
+                        eprintln!("Couldn't cache ground truth at {}: {}", cache, e);
+                        GroundTruth::compute(data, &queries, config.k, config.metric)
+                    });
+            println!("Loaded ground truth in {:.2?}.", start.elapsed());
+            ground_truth
+        }
+    };
+
+    if let Some(prefix) = &config.export {
+        ground_truth.save_ivecs(&format!("{prefix}.ivecs")).unwrap();
+        // ground truth loaded from .ivecs has no distances
+        if !ground_truth.distances.is_empty() {
+            ground_truth.save_fvecs(&format!("{prefix}.fvecs")).unwrap();
+        }
+    }
+
+    if config.sweep {
+        let grid: Grid = match &config.grid {
+            Some(path) => serde_json::from_reader(File::open(path).unwrap()).unwrap(),
+            None => Grid::default(),
+        };
+        let sweep = Sweep {
+            data,
+            queries: &queries,
+            ground_truth: &ground_truth,
+            k: config.k,
+            metric: config.metric,
+        };
+        let points = sweep.run(&grid, |p| {
+            println!(
+                "{} {}: recall@{}: {:.4}, {:.1} queries/s, built in {:.2}s, {} bytes",
+                p.algorithm, p.params, config.k, p.recall, p.qps, p.build_time, p.memory
+            )
+        });
+
+        println!("Pareto frontier:");
+        for p in points.iter().filter(|p| p.pareto) {
+            println!(
+                "  {} {}: recall@{}: {:.4}, {:.1} queries/s",
+                p.algorithm, p.params, config.k, p.recall, p.qps
+            );
+        }
+        if let Some(path) = &config.csv {
+            sweep::write_csv(path, &points).unwrap();
+        }
+        if let Some(path) = &config.json {
+            sweep::write_json(path, &points).unwrap();
+        }
+        return;
+    }
+
+    // indexes rank by the metric of the ground truth, exact search to be exact
+    let index =
+        get_search_algorithm_with_metric(algorithm, data, config.metric).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            process::exit(1);
+        });
+    let evaluation = eval::evaluate(
+        index.as_ref(),
+        data,
+        &queries,
+        &ground_truth,
+        config.k,
+        config.metric,
+    );
+    println!("{}: {}", algorithm, evaluation);
+    println!("Memory: {}", index.memory_usage());
+    let stats = eval::total_stats(index.as_ref(), &queries, config.k);
+    println!("Per query: {}", stats.per_query(queries.len()));
+}
+
+fn run_ann(config: &AnnConfig) {
+    let name = config.name.clone().unwrap_or_else(|| {
+        Path::new(&config.train)
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    });
+    let dataset = AnnDataset::load(&name, &config.train, &config.test, &config.neighbors).unwrap();
+    println!(
+        "Loaded {}: {} train vectors, {} test queries.",
+        name,
+        dataset.train.len(),
+        dataset.test.len()
+    );
+
+    let grid: Grid = match &config.grid {
+        Some(path) => serde_json::from_reader(File::open(path).unwrap()).unwrap(),
+        None => Grid::default(),
+    };
+    let runner = AnnRunner {
+        dataset: &dataset,
+        k: config.k,
+        metric: config.metric,
+        runs: config.runs,
+    };
+    runner.run(&grid, |result| {
+        let path = result.write(&config.output).unwrap();
+        println!(
+            "{}: recall@{}: {:.4}, {:.1} queries/s, built in {:.2}s -> {}",
+            result.name,
+            config.k,
+            runner.recall(&result),
+            1.0 / result.best_search_time,
+            result.build_time,
+            path.display()
+        );
+    });
+}
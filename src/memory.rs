@@ -0,0 +1,64 @@
+use std::{collections::HashMap, fmt, mem::size_of};
+
+use serde::Serialize;
+
+/// Estimated heap bytes held by an index, split by what they are used for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryUsage {
+    pub vectors: usize,
+    pub nodes: usize,
+    pub graph: usize,
+    pub buckets: usize,
+    pub plane_norms: usize,
+    /// buffers kept between searches
+    pub scratch: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.vectors + self.nodes + self.graph + self.buckets + self.plane_norms + self.scratch
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", human_readable(self.total()))?;
+
+        let parts = [
+            ("vectors", self.vectors),
+            ("nodes", self.nodes),
+            ("graph", self.graph),
+            ("buckets", self.buckets),
+            ("plane norms", self.plane_norms),
+            ("scratch", self.scratch),
+        ];
+        let parts: Vec<String> = parts
+            .iter()
+            .filter(|(_, bytes)| *bytes > 0)
+            .map(|(name, bytes)| format!("{name}: {}", human_readable(*bytes)))
+            .collect();
+        write!(f, " ({})", parts.join(", "))
+    }
+}
+
+fn human_readable(bytes: usize) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB"] {
+        if size < 1024.0 {
+            return format!("{size:.2} {unit}");
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.2} GiB")
+}
+
+/// Heap bytes of a `Vec`'s buffer, not including what its elements own.
+pub fn vec_bytes<T>(v: &Vec<T>) -> usize {
+    v.capacity() * size_of::<T>()
+}
+
+/// Approximate heap bytes of a `HashMap`'s table, not including what its
+/// keys and values own. Every slot also has a control byte.
+pub fn hashmap_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
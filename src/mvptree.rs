@@ -0,0 +1,454 @@
+use rand::Rng;
+
+use crate::{
+    memory::{vec_bytes, MemoryUsage},
+    stats::{SearchStats, Stats},
+    vptree::{Distance, Points},
+    Algorithm, LimitedHeap, Metric, OrdItem, VectorID, Vectors,
+};
+
+/// Multi-vantage-point tree (Bozkaya & Özsoyoglu). Every node partitions
+/// its points m ways by their distance to one vantage point and each part m
+/// ways by the distance to a second one. Leaf points keep their distances
+/// to the vantage points above them, so that most of them can be ruled out
+/// without computing their distance to the query. Like `VPTree`, it works
+/// over any points with a true metric.
+pub struct MvpTree<P: Points = Vectors, M = Metric> {
+    nodes: Vec<MvpNode>,
+    /// children of the internal nodes, with the distance ranges they cover
+    children: Vec<Child>,
+    points: P,
+    /// id of the point in each row
+    ids: Vec<VectorID>,
+    /// distances from each row to the first `path_length` vantage points on
+    /// the way down to it, NaN where the path is shorter
+    paths: Vec<f32>,
+    /// distances from each row to the vantage points of its leaf
+    leaf_distances: Vec<[f32; 2]>,
+    path_length: usize,
+    metric: M,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MvpTreeConfig {
+    /// no. of parts each vantage point splits the points into, so every
+    /// internal node has up to order² children
+    pub order: usize,
+    /// max no. of points in a leaf, including its two vantage points
+    pub leaf_size: usize,
+    /// no. of distances to ancestor vantage points kept for every point
+    pub path_length: usize,
+}
+
+impl Default for MvpTreeConfig {
+    fn default() -> Self {
+        Self {
+            order: 3,
+            leaf_size: 16,
+            path_length: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MvpNode {
+    /// rows of the two vantage points and the range of their children
+    Internal {
+        vantage: [u32; 2],
+        start: u32,
+        end: u32,
+    },
+    /// rows of the leaf, of which the first two are its vantage points
+    Leaf { start: u32, end: u32 },
+}
+
+#[derive(Debug, Clone)]
+struct Child {
+    /// min and max distance of the child's points to each vantage point
+    bounds: [(f32, f32); 2],
+    node: u32,
+}
+
+/// A point being placed in the tree and its distances to the vantage points
+/// of the node being built
+struct Item<'a, T: ?Sized> {
+    id: VectorID,
+    point: &'a T,
+    /// index of the point's path among the paths being built
+    index: usize,
+    distances: [f32; 2],
+}
+
+impl Algorithm for MvpTree {
+    fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
+        self.nearest_neighbors(query, k, &mut ())
+            .into_iter()
+            .map(|OrdItem(_, id)| id)
+            .collect()
+    }
+
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats) {
+        let mut stats = SearchStats::default();
+        let result = self.nearest_neighbors(query, k, &mut stats);
+        (result.into_iter().map(|OrdItem(_, id)| id).collect(), stats)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            vectors: self.points.bytes() + vec_bytes(&self.ids),
+            nodes: vec_bytes(&self.nodes)
+                + vec_bytes(&self.children)
+                + vec_bytes(&self.paths)
+                + vec_bytes(&self.leaf_distances),
+            ..Default::default()
+        }
+    }
+}
+
+impl MvpTree {
+    pub fn load(data: &[(VectorID, Vec<f32>)]) -> Self {
+        Self::with_config(data, MvpTreeConfig::default())
+    }
+
+    pub fn with_config(data: &[(VectorID, Vec<f32>)], config: MvpTreeConfig) -> Self {
+        Self::with_metric(data, Metric::SquaredEuclidean, config)
+    }
+
+    /// Neighbors are ranked by `metric`, searched through its true metric
+    /// as in `VPTree::with_metric`.
+    pub fn with_metric(
+        data: &[(VectorID, Vec<f32>)],
+        metric: Metric,
+        config: MvpTreeConfig,
+    ) -> Self {
+        let dimensions = data.first().map_or(0, |(_, v)| v.len());
+        let points = Vectors::with_capacity(dimensions, data.len());
+        let items = data.iter().map(|(id, v)| (*id, v.as_slice()));
+        Self::build_tree(points, metric.true_metric(), items, config)
+    }
+}
+
+impl<T: Clone, M: Distance<T>> MvpTree<Vec<T>, M> {
+    /// Index arbitrary points, such as strings under edit distance.
+    pub fn with_distance(data: &[(VectorID, T)], metric: M, config: MvpTreeConfig) -> Self {
+        let items = data.iter().map(|(id, t)| (*id, t));
+        Self::build_tree(Vec::with_capacity(data.len()), metric, items, config)
+    }
+}
+
+impl<P: Points, M: Distance<P::Point>> MvpTree<P, M> {
+    fn build_tree<'a>(
+        points: P,
+        metric: M,
+        items: impl ExactSizeIterator<Item = (VectorID, &'a P::Point)>,
+        config: MvpTreeConfig,
+    ) -> Self
+    where
+        P::Point: 'a,
+    {
+        let n = items.len();
+        let mut items: Vec<Item<P::Point>> = items
+            .enumerate()
+            .map(|(index, (id, point))| Item {
+                id,
+                point,
+                index,
+                distances: [f32::NAN; 2],
+            })
+            .collect();
+        let mut paths = vec![f32::NAN; n * config.path_length];
+
+        let mut s = Self {
+            nodes: Vec::new(),
+            children: Vec::new(),
+            points,
+            ids: Vec::with_capacity(n),
+            paths: Vec::with_capacity(n * config.path_length),
+            leaf_distances: Vec::with_capacity(n),
+            path_length: config.path_length,
+            metric,
+        };
+        if n > 0 {
+            s.build(&mut items, &mut paths, 0, &config, &mut rand::thread_rng());
+        }
+        s
+    }
+
+    /// The k nearest neighbors and their distances, closest first
+    pub fn search_with_distances(&self, query: &P::Point, k: usize) -> Vec<OrdItem<VectorID>> {
+        self.nearest_neighbors(query, k, &mut ())
+    }
+
+    /// Nodes are added in pre-order and so are the rows, so that a
+    /// subtree's points are next to each other in memory.
+    fn build(
+        &mut self,
+        items: &mut [Item<P::Point>],
+        paths: &mut [f32],
+        depth: usize,
+        config: &MvpTreeConfig,
+        rng: &mut impl Rng,
+    ) -> usize {
+        let node = self.nodes.len();
+
+        if items.len() <= config.leaf_size.max(2) {
+            let start = self.ids.len() as u32;
+            let filtered = items.len() > 2;
+            if filtered {
+                self.first_vantage(items, rng);
+                self.second_vantage(&mut items[1..]);
+            }
+            for (i, item) in items.iter().enumerate() {
+                // the vantage points themselves are always compared
+                let distances = if filtered && i >= 2 {
+                    item.distances
+                } else {
+                    [f32::NAN; 2]
+                };
+                self.push_row(item, paths, distances);
+            }
+            let end = self.ids.len() as u32;
+            self.nodes.push(MvpNode::Leaf { start, end });
+            return node;
+        }
+
+        self.first_vantage(items, rng);
+        self.second_vantage(&mut items[1..]);
+        for item in &items[2..] {
+            for (level, d) in item.distances.into_iter().enumerate() {
+                let i = 2 * depth + level;
+                if i < config.path_length {
+                    paths[item.index * config.path_length + i] = d;
+                }
+            }
+        }
+
+        let vantage = [self.ids.len() as u32, self.ids.len() as u32 + 1];
+        for item in &items[..2] {
+            self.push_row(item, paths, [f32::NAN; 2]);
+        }
+        self.nodes.push(MvpNode::Internal {
+            vantage,
+            start: 0,
+            end: 0,
+        });
+
+        let order = config.order.max(2);
+        let mut children = Vec::with_capacity(order * order);
+        let rest = &mut items[2..];
+        rest.sort_unstable_by(|a, b| a.distances[0].total_cmp(&b.distances[0]));
+        for part in rest.chunks_mut(rest.len().div_ceil(order)) {
+            part.sort_unstable_by(|a, b| a.distances[1].total_cmp(&b.distances[1]));
+            for part in part.chunks_mut(part.len().div_ceil(order)) {
+                let mut bounds = [(f32::INFINITY, f32::NEG_INFINITY); 2];
+                for item in part.iter() {
+                    for (bound, d) in bounds.iter_mut().zip(item.distances) {
+                        *bound = (bound.0.min(d), bound.1.max(d));
+                    }
+                }
+                let child = self.build(part, paths, depth + 1, config, rng) as u32;
+                children.push(Child {
+                    bounds,
+                    node: child,
+                });
+            }
+        }
+
+        let start = self.children.len() as u32;
+        self.children.extend(children);
+        let end = self.children.len() as u32;
+        self.nodes[node] = MvpNode::Internal {
+            vantage,
+            start,
+            end,
+        };
+        node
+    }
+
+    /// Move a random point to the front as the first vantage point, with
+    /// the distances to it as the first distances of the others.
+    fn first_vantage(&self, items: &mut [Item<P::Point>], rng: &mut impl Rng) {
+        items.swap(0, rng.gen_range(0..items.len()));
+        let (vantage, rest) = items.split_first_mut().unwrap();
+        for item in rest {
+            item.distances[0] = self.metric.distance(vantage.point, item.point);
+        }
+    }
+
+    /// Move the point farthest from the first vantage point to the front as
+    /// the second one, with the distances to it as the second distances of
+    /// the others.
+    fn second_vantage(&self, items: &mut [Item<P::Point>]) {
+        let farthest = (0..items.len())
+            .max_by(|a, b| items[*a].distances[0].total_cmp(&items[*b].distances[0]))
+            .unwrap();
+        items.swap(0, farthest);
+        let (vantage, rest) = items.split_first_mut().unwrap();
+        for item in rest {
+            item.distances[1] = self.metric.distance(vantage.point, item.point);
+        }
+    }
+
+    fn push_row(&mut self, item: &Item<P::Point>, paths: &[f32], leaf_distances: [f32; 2]) {
+        let path = &paths[item.index * self.path_length..(item.index + 1) * self.path_length];
+        self.points.push(item.point);
+        self.ids.push(item.id);
+        self.paths.extend_from_slice(path);
+        self.leaf_distances.push(leaf_distances);
+    }
+
+    fn nearest_neighbors(
+        &self,
+        target: &P::Point,
+        k: usize,
+        stats: &mut impl Stats,
+    ) -> Vec<OrdItem<VectorID>> {
+        if k == 0 || self.nodes.is_empty() {
+            return vec![];
+        }
+
+        let mut search = Search {
+            target,
+            neighbors: LimitedHeap::new(k),
+            tau: f32::INFINITY,
+            path: Vec::with_capacity(self.path_length),
+        };
+        self.search_node(0, &mut search, stats);
+        search.neighbors.consume().into_sorted_vec()
+    }
+
+    fn search_node(&self, node: usize, search: &mut Search<P::Point>, stats: &mut impl Stats) {
+        stats.visit();
+        match self.nodes[node] {
+            MvpNode::Leaf { start, end } => {
+                let (start, end) = (start as usize, end as usize);
+                let vantage = start..end.min(start + 2);
+                let mut distances = [f32::NAN; 2];
+                for (d, row) in distances.iter_mut().zip(vantage.clone()) {
+                    *d = self.compare(row, search, stats);
+                }
+
+                for row in vantage.end..end {
+                    // by the triangle inequality, a point can't be closer to
+                    // the target than the difference of their distances to
+                    // any vantage point
+                    let path = &self.paths[row * self.path_length..(row + 1) * self.path_length];
+                    let ruled_out = distances
+                        .iter()
+                        .zip(&self.leaf_distances[row])
+                        .chain(search.path.iter().zip(path))
+                        .any(|(target, point)| (target - point).abs() >= search.tau);
+                    if !ruled_out {
+                        self.compare(row, search, stats);
+                    }
+                }
+            }
+            MvpNode::Internal {
+                vantage,
+                start,
+                end,
+            } => {
+                let distances = vantage.map(|row| self.compare(row as usize, search, stats));
+                let depth = search.path.len();
+                for d in distances {
+                    if search.path.len() < self.path_length {
+                        search.path.push(d);
+                    }
+                }
+
+                // children whose distance ranges are closest to the target's
+                // distances first, which shrinks tau sooner
+                let mut children: Vec<(f32, usize)> = self.children[start as usize..end as usize]
+                    .iter()
+                    .map(|child| {
+                        let gap = child
+                            .bounds
+                            .iter()
+                            .zip(distances)
+                            .map(|((lo, hi), d)| (lo - d).max(d - hi))
+                            .fold(0.0, f32::max);
+                        (gap, child.node as usize)
+                    })
+                    .collect();
+                children.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+                for (gap, child) in children {
+                    if gap < search.tau {
+                        self.search_node(child, search, stats);
+                    } else {
+                        stats.prune();
+                    }
+                }
+                search.path.truncate(depth);
+            }
+        }
+    }
+
+    /// Add the row to the neighbors if it's closer than tau, shrinking tau
+    /// once there are k of them, and return its distance.
+    fn compare(&self, row: usize, search: &mut Search<P::Point>, stats: &mut impl Stats) -> f32 {
+        stats.distance();
+        let d = self.metric.distance(search.target, self.points.row(row));
+        if d < search.tau {
+            search.neighbors.push(OrdItem(d, self.ids[row]));
+            if search.neighbors.is_full() {
+                search.tau = search.neighbors.peek().unwrap().0;
+            }
+        }
+        d
+    }
+}
+
+struct Search<'a, T: ?Sized> {
+    target: &'a T,
+    neighbors: LimitedHeap<OrdItem<VectorID>>,
+    /// distance of the k-th neighbor so far
+    tau: f32,
+    /// distances from the target to the vantage points on the way down, as
+    /// many as the points keep
+    path: Vec<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// No. of positions at which two equally long words differ
+    fn hamming_distance(a: &[u8; 12], b: &[u8; 12]) -> f32 {
+        a.iter().zip(b).filter(|(x, y)| x != y).count() as f32
+    }
+
+    #[test]
+    fn test_words_by_hamming_distance() {
+        let mut rng = rand::thread_rng();
+        let words: Vec<(VectorID, [u8; 12])> = (0..2000)
+            .map(|id| (id, [0; 12].map(|_| rng.gen_range(b'a'..=b'd'))))
+            .collect();
+
+        for config in [
+            MvpTreeConfig::default(),
+            MvpTreeConfig {
+                order: 2,
+                leaf_size: 3,
+                path_length: 0,
+            },
+        ] {
+            let tree = MvpTree::with_distance(&words, hamming_distance, config);
+            for (_, query) in words.iter().step_by(100) {
+                let found: Vec<f32> = tree
+                    .search_with_distances(query, 10)
+                    .iter()
+                    .map(|item| item.0)
+                    .collect();
+
+                let mut expected: Vec<f32> = words
+                    .iter()
+                    .map(|(_, word)| hamming_distance(query, word))
+                    .collect();
+                expected.sort_by(f32::total_cmp);
+                expected.truncate(10);
+                assert_eq!(found, expected, "{config:?}");
+            }
+        }
+    }
+}
@@ -5,50 +5,102 @@ use std::{
 
 use rand::seq::SliceRandom;
 
-use crate::{distance, Algorithm, LimitedHeap, OrdItem, VectorID};
+use crate::{
+    distance,
+    memory::{hashmap_bytes, vec_bytes, MemoryUsage},
+    stats::{SearchStats, Stats},
+    Algorithm, LimitedHeap, OrdItem, VectorID,
+};
 
 pub struct NSW {
     graph: HashMap<VectorID, Vec<VectorID>>,
     map: HashMap<VectorID, Vec<f32>>,
     index: Vec<VectorID>,
     dimensionality: usize,
+    search_attempts: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NSWConfig {
+    /// no. of neighbors each inserted vertex is connected to
+    pub neighbors: usize,
+    /// multiplier for the no. of search attempts made while inserting
+    pub build_attempts: usize,
+    /// multiplier for the no. of search attempts made while searching
+    pub search_attempts: usize,
+}
+
+impl Default for NSWConfig {
+    fn default() -> Self {
+        Self {
+            neighbors: 10,
+            build_attempts: 1,
+            search_attempts: 1,
+        }
+    }
 }
 
 impl Algorithm for NSW {
     fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
-        let a = 1;
-        let w = a * max(1, max(1, self.graph.len()).ilog10());
-        self.multi_search(query, w as usize, k)
-            .into_iter()
-            .map(|OrdItem(_, id)| id)
-            .collect()
+        self.nearest_neighbors(query, k, &mut ())
+    }
+
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats) {
+        let mut stats = SearchStats::default();
+        (self.nearest_neighbors(query, k, &mut stats), stats)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            vectors: hashmap_bytes(&self.map) + self.map.values().map(vec_bytes).sum::<usize>(),
+            graph: hashmap_bytes(&self.graph)
+                + self.graph.values().map(vec_bytes).sum::<usize>()
+                + vec_bytes(&self.index),
+            ..Default::default()
+        }
     }
 }
 
 impl NSW {
     pub fn load(data: &[(VectorID, Vec<f32>)]) -> Self {
-        let dimensionality = data.first().unwrap().1.len();
+        Self::with_config(data, NSWConfig::default())
+    }
+
+    pub fn with_config(data: &[(VectorID, Vec<f32>)], config: NSWConfig) -> Self {
+        let dimensionality = data.first().map_or(0, |(_, v)| v.len());
         let mut s = NSW {
             graph: HashMap::with_capacity(data.len()),
             map: HashMap::with_capacity(data.len()),
             index: Vec::with_capacity(data.len()),
             dimensionality,
+            search_attempts: config.search_attempts,
         };
 
         for item in data.iter() {
-            let k = 10;
-            let a = 1;
-            // no. of search attempts
-            // failure probability decreases exponentially as no. of search attempts increases
-            let w = a * max(1, max(1, s.graph.len()).ilog10());
-            s.insert(item, k, w as usize);
+            let w = Self::attempts(config.build_attempts, s.graph.len());
+            s.insert(item, config.neighbors, w);
             s.index.push(item.0);
         }
 
         s
     }
 
+    /// Changing the no. of search attempts doesn't require rebuilding the graph.
+    pub fn set_search_attempts(&mut self, search_attempts: usize) {
+        self.search_attempts = search_attempts;
+    }
+
+    // no. of search attempts
+    // failure probability decreases exponentially as no. of search attempts increases
+    fn attempts(a: usize, graph_size: usize) -> usize {
+        a * max(1, max(1, graph_size).ilog10()) as usize
+    }
+
     pub fn insert(&mut self, object: &(VectorID, Vec<f32>), k: usize, w: usize) {
+        // a graph built from no vectors takes the dimensions of the first
+        if self.map.is_empty() {
+            self.dimensionality = object.1.len();
+        }
         assert_eq!(self.dimensionality, object.1.len());
 
         self.map.insert(object.0, object.1.clone());
@@ -67,7 +119,7 @@ impl NSW {
             return;
         }
 
-        let u = self.multi_search(&object.1, w, k);
+        let u = self.multi_search(&object.1, w, k, &mut ());
 
         u.iter().for_each(|&OrdItem(_, v)| {
             self.graph.entry(v).and_modify(|e| e.push(object.0));
@@ -75,7 +127,25 @@ impl NSW {
         });
     }
 
-    fn multi_search(&self, query: &[f32], m: usize, k: usize) -> Vec<OrdItem<VectorID>> {
+    fn nearest_neighbors(&self, query: &[f32], k: usize, stats: &mut impl Stats) -> Vec<VectorID> {
+        if k == 0 || self.index.is_empty() {
+            return vec![];
+        }
+
+        let w = Self::attempts(self.search_attempts, self.graph.len());
+        self.multi_search(query, w, k, stats)
+            .into_iter()
+            .map(|OrdItem(_, id)| id)
+            .collect()
+    }
+
+    fn multi_search(
+        &self,
+        query: &[f32],
+        m: usize,
+        k: usize,
+        stats: &mut impl Stats,
+    ) -> Vec<OrdItem<VectorID>> {
         let mut results: LimitedHeap<OrdItem<VectorID>> = LimitedHeap::new(k);
         let mut visited = HashSet::new();
         let mut rng = rand::thread_rng();
@@ -86,7 +156,7 @@ impl NSW {
                 continue;
             }
 
-            self.greedy_search(query, *entry_point, k, &mut results, &mut visited);
+            self.greedy_search(query, *entry_point, k, &mut results, &mut visited, stats);
 
             if visited.len() == self.graph.len() {
                 break;
@@ -103,16 +173,19 @@ impl NSW {
         k: usize,
         results: &mut LimitedHeap<OrdItem<VectorID>>,
         visited: &mut HashSet<VectorID>,
+        stats: &mut impl Stats,
     ) {
         let mut candidates = BinaryHeap::new();
 
+        stats.visit();
+        stats.distance();
         let metric = self.metric(query, &entry_point);
         candidates.push(OrdItem(-metric, entry_point));
         results.push(OrdItem(metric, entry_point));
 
         while let Some(best_candidate) = candidates.pop() {
             let OrdItem(metric_cn, v_curr) = best_candidate;
-            let metric_cn = -1.0 * metric_cn;
+            let metric_cn = -metric_cn;
 
             let kth_best = if results.len() >= k {
                 results.peek().unwrap().0
@@ -124,11 +197,14 @@ impl NSW {
                 break;
             }
 
+            stats.hop();
             for v_friend in self.get_friends(&v_curr) {
                 if visited.contains(v_friend) {
                     continue;
                 }
                 visited.insert(*v_friend);
+                stats.visit();
+                stats.distance();
                 let metric_fr = self.metric(query, v_friend);
 
                 candidates.push(OrdItem(-metric_fr, *v_friend));
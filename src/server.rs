@@ -0,0 +1,194 @@
+use std::{io, thread};
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    index::{Index, Neighbor, Query},
+    memory::MemoryUsage,
+};
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    #[serde(flatten)]
+    query: Query,
+    #[serde(default = "default_k")]
+    k: usize,
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    queries: Vec<Query>,
+    #[serde(default = "default_k")]
+    k: usize,
+}
+
+#[derive(Serialize)]
+struct SearchResponse<'a> {
+    results: Vec<Neighbor<'a>>,
+}
+
+#[derive(Serialize)]
+struct BatchResponse<'a> {
+    results: Vec<Vec<Neighbor<'a>>>,
+}
+
+#[derive(Serialize)]
+struct InfoResponse<'a> {
+    algorithm: &'a str,
+    vectors: usize,
+    dimensions: usize,
+    memory: MemoryUsage,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+fn default_k() -> usize {
+    5
+}
+
+impl Index {
+    fn handle(&self, method: &Method, url: &str, body: &str) -> (u16, String) {
+        let result = match (method, url) {
+            (Method::Get, "/health") => Ok(r#"{"status":"ok"}"#.to_owned()),
+            (Method::Get, "/info") => to_json(&InfoResponse {
+                algorithm: self.name(),
+                vectors: self.len(),
+                dimensions: self.dimensions(),
+                memory: self.memory_usage(),
+            }),
+            (Method::Post, "/search") => serde_json::from_str::<SearchRequest>(body)
+                .map_err(|e| e.to_string())
+                .and_then(|req| self.search(&req.query, req.k))
+                .and_then(|results| to_json(&SearchResponse { results })),
+            (Method::Post, "/search_batch") => serde_json::from_str::<BatchRequest>(body)
+                .map_err(|e| e.to_string())
+                .and_then(|req| {
+                    req.queries
+                        .iter()
+                        .map(|query| self.search(query, req.k))
+                        .collect()
+                })
+                .and_then(|results| to_json(&BatchResponse { results })),
+            (_, "/health" | "/info" | "/search" | "/search_batch") => {
+                return (405, error_json("method not allowed"))
+            }
+            _ => return (404, error_json("not found")),
+        };
+
+        match result {
+            Ok(json) => (200, json),
+            Err(e) => (400, error_json(&e)),
+        }
+    }
+
+    fn respond(&self, mut request: Request) -> io::Result<()> {
+        let mut body = String::new();
+        let (status, json) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => self.handle(request.method(), request.url(), &body),
+            Err(_) => (400, error_json("request body is not valid UTF-8")),
+        };
+
+        let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+        request.respond(
+            Response::from_string(json)
+                .with_status_code(status)
+                .with_header(header),
+        )
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, String> {
+    serde_json::to_string(value).map_err(|e| e.to_string())
+}
+
+fn error_json(message: &str) -> String {
+    serde_json::to_string(&ErrorResponse {
+        error: message.to_owned(),
+    })
+    .unwrap()
+}
+
+/// Serve `index` on `addr` until the process is killed, answering requests
+/// from `workers` threads that share the same index.
+pub fn serve(index: &Index, addr: &str, workers: usize) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+
+    thread::scope(|s| {
+        for _ in 0..workers.max(1) {
+            s.spawn(|| {
+                for request in server.incoming_requests() {
+                    if let Err(e) = index.respond(request) {
+                        eprintln!("failed to send response: {e}");
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kdtree::KDTree;
+    use crate::VectorID;
+
+    fn index() -> Index {
+        let data = vec![
+            ("a".to_owned(), vec![1.0, 0.0]),
+            ("b".to_owned(), vec![0.0, 1.0]),
+            ("c".to_owned(), vec![0.9, 0.1]),
+        ];
+        let ids: Vec<(VectorID, Vec<f32>)> = data
+            .iter()
+            .enumerate()
+            .map(|(idx, (_label, vector))| (idx, vector.clone()))
+            .collect();
+        Index::new(Box::new(KDTree::load(&ids)), "kdtree", data)
+    }
+
+    #[test]
+    fn test_search_routes() {
+        let index = index();
+
+        let (status, body) = index.handle(&Method::Post, "/search", r#"{"label":"a","k":2}"#);
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            r#"{"results":[{"id":0,"label":"a"},{"id":2,"label":"c"}]}"#
+        );
+
+        let (status, _) = index.handle(&Method::Post, "/search", r#"{"vector":[1.0],"k":2}"#);
+        assert_eq!(status, 400);
+
+        let (status, body) = index.handle(
+            &Method::Post,
+            "/search_batch",
+            r#"{"queries":[{"label":"b"},{"vector":[1.0,0.0]}],"k":1}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            r#"{"results":[[{"id":1,"label":"b"}],[{"id":0,"label":"a"}]]}"#
+        );
+
+        let (status, body) = index.handle(
+            &Method::Post,
+            "/search",
+            r#"{"label":"a","k":1000000000000}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            r#"{"results":[{"id":0,"label":"a"},{"id":2,"label":"c"},{"id":1,"label":"b"}]}"#
+        );
+
+        assert_eq!(index.handle(&Method::Get, "/search", "").0, 405);
+        assert_eq!(index.handle(&Method::Get, "/health", "").0, 200);
+    }
+}
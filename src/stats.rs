@@ -0,0 +1,97 @@
+use std::{fmt, ops::AddAssign};
+
+use serde::Serialize;
+
+/// What a single search did, to explain its cost and accuracy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SearchStats {
+    pub distance_computations: usize,
+    pub nodes_visited: usize,
+    pub branches_pruned: usize,
+    pub buckets_probed: usize,
+    pub graph_hops: usize,
+}
+
+/// Receives search events. Searches are generic over it so that with `()`
+/// the counting compiles away entirely.
+pub trait Stats {
+    fn distance(&mut self);
+    fn visit(&mut self);
+    fn prune(&mut self);
+    fn probe(&mut self);
+    fn hop(&mut self);
+}
+
+impl Stats for () {
+    #[inline(always)]
+    fn distance(&mut self) {}
+    #[inline(always)]
+    fn visit(&mut self) {}
+    #[inline(always)]
+    fn prune(&mut self) {}
+    #[inline(always)]
+    fn probe(&mut self) {}
+    #[inline(always)]
+    fn hop(&mut self) {}
+}
+
+impl Stats for SearchStats {
+    fn distance(&mut self) {
+        self.distance_computations += 1;
+    }
+
+    fn visit(&mut self) {
+        self.nodes_visited += 1;
+    }
+
+    fn prune(&mut self) {
+        self.branches_pruned += 1;
+    }
+
+    fn probe(&mut self) {
+        self.buckets_probed += 1;
+    }
+
+    fn hop(&mut self) {
+        self.graph_hops += 1;
+    }
+}
+
+impl SearchStats {
+    /// Average of every counter over `queries` searches
+    pub fn per_query(&self, queries: usize) -> String {
+        let n = queries.max(1) as f64;
+        format!(
+            "distance computations: {:.1}, nodes visited: {:.1}, branches pruned: {:.1}, buckets probed: {:.1}, graph hops: {:.1}",
+            self.distance_computations as f64 / n,
+            self.nodes_visited as f64 / n,
+            self.branches_pruned as f64 / n,
+            self.buckets_probed as f64 / n,
+            self.graph_hops as f64 / n
+        )
+    }
+}
+
+impl AddAssign for SearchStats {
+    fn add_assign(&mut self, other: Self) {
+        self.distance_computations += other.distance_computations;
+        self.nodes_visited += other.nodes_visited;
+        self.branches_pruned += other.branches_pruned;
+        self.buckets_probed += other.buckets_probed;
+        self.graph_hops += other.graph_hops;
+    }
+}
+
+impl fmt::Display for SearchStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "distance computations: {}, nodes visited: {}, branches pruned: {}, buckets probed: {}, graph hops: {}",
+            self.distance_computations,
+            self.nodes_visited,
+            self.branches_pruned,
+            self.buckets_probed,
+            self.graph_hops
+        )
+    }
+}
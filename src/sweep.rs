@@ -0,0 +1,386 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    eval,
+    exact::Exact,
+    groundtruth::GroundTruth,
+    kdforest::{KDForest, KDForestConfig},
+    kdtree::{KDTree, KDTreeConfig},
+    lsh::{LSHConfig, LSH},
+    mvptree::{MvpTree, MvpTreeConfig},
+    nsw::{NSWConfig, NSW},
+    supports_metric,
+    vptree::{VPTree, VPTreeConfig, Vantage},
+    Algorithm, Metric, VectorID,
+};
+
+/// Build and search parameters to try. Every combination of the parameters
+/// of an algorithm is one point of the sweep.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Grid {
+    pub algorithms: Vec<String>,
+    pub kdtree_leaf_size: Vec<usize>,
+    pub kdtree_epsilon: Vec<f32>,
+    pub vptree_leaf_size: Vec<usize>,
+    /// "random", "spread" or "farthest"
+    pub vptree_vantage: Vec<String>,
+    pub vptree_epsilon: Vec<f32>,
+    /// `null` searches the whole tree
+    pub vptree_max_visits: Vec<Option<usize>>,
+    pub mvptree_order: Vec<usize>,
+    pub mvptree_path_length: Vec<usize>,
+    pub kdforest_trees: Vec<usize>,
+    pub kdforest_max_checks: Vec<usize>,
+    pub nsw_neighbors: Vec<usize>,
+    pub nsw_build_attempts: Vec<usize>,
+    pub nsw_search_attempts: Vec<usize>,
+    pub lsh_tables: Vec<usize>,
+    pub lsh_bits_per_table: Vec<usize>,
+    pub lsh_candidate_factor: Vec<usize>,
+    pub lsh_probes: Vec<usize>,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Self {
+            algorithms: [
+                "exact", "kdtree", "kdforest", "vptree", "mvptree", "lsh", "nsw",
+            ]
+            .map(String::from)
+            .to_vec(),
+            kdtree_leaf_size: vec![1, 4, 16, 64],
+            kdtree_epsilon: vec![0.0, 0.5, 2.0],
+            vptree_leaf_size: vec![1, 4, 16, 64],
+            vptree_vantage: Vantage::ALL.map(|v| v.name().to_owned()).to_vec(),
+            vptree_epsilon: vec![0.0, 1.0],
+            vptree_max_visits: vec![None, Some(64), Some(512)],
+            mvptree_order: vec![2, 3, 4],
+            mvptree_path_length: vec![0, 8],
+            kdforest_trees: vec![1, 4, 8],
+            kdforest_max_checks: vec![32, 128, 512, 2048],
+            nsw_neighbors: vec![5, 10, 20],
+            nsw_build_attempts: vec![1, 2],
+            nsw_search_attempts: vec![1, 2, 4, 8],
+            lsh_tables: vec![1, 4, 16],
+            lsh_bits_per_table: vec![8, 12, 16],
+            lsh_candidate_factor: vec![1, 10, 50],
+            lsh_probes: vec![1, 16, 64],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SweepPoint {
+    pub algorithm: String,
+    pub params: String,
+    /// seconds
+    pub build_time: f64,
+    /// estimated heap bytes of the index, see `Algorithm::memory_usage`
+    pub memory: usize,
+    pub qps: f64,
+    pub recall: f64,
+    /// no other point has both higher recall and higher QPS
+    pub pareto: bool,
+}
+
+/// Dataset, queries and ground truth shared by every point of a sweep.
+pub struct Sweep<'a> {
+    pub data: &'a [(VectorID, Vec<f32>)],
+    pub queries: &'a [Vec<f32>],
+    pub ground_truth: &'a GroundTruth,
+    pub k: usize,
+    pub metric: Metric,
+}
+
+impl Sweep<'_> {
+    /// Evaluate every point of the grid, calling `on_point` as each finishes,
+    /// and return all points with the Pareto-optimal ones marked.
+    pub fn run(&self, grid: &Grid, mut on_point: impl FnMut(&SweepPoint)) -> Vec<SweepPoint> {
+        let mut points = Vec::new();
+        for_each_index(
+            grid,
+            self.data,
+            self.metric,
+            |algorithm, params, build, index| {
+                let point = self.point(algorithm, params, build, index);
+                on_point(&point);
+                points.push(point);
+            },
+        );
+
+        mark_pareto(&mut points);
+        points
+    }
+
+    fn point(
+        &self,
+        algorithm: &str,
+        params: String,
+        build: &Build,
+        index: &dyn Algorithm,
+    ) -> SweepPoint {
+        let evaluation = eval::evaluate(
+            index,
+            self.data,
+            self.queries,
+            self.ground_truth,
+            self.k,
+            self.metric,
+        );
+
+        SweepPoint {
+            algorithm: algorithm.to_owned(),
+            params,
+            build_time: build.time.as_secs_f64(),
+            memory: index.memory_usage().total(),
+            qps: evaluation.qps(),
+            recall: evaluation.recall,
+            pareto: false,
+        }
+    }
+}
+
+/// Cost of building one index of the grid.
+pub struct Build {
+    pub time: Duration,
+}
+
+/// Build every index configuration of the grid in turn and hand it to `f`
+/// along with its algorithm name and parameters. Search-only parameters
+/// reuse the same build. Algorithms that don't support `metric` are skipped.
+pub fn for_each_index(
+    grid: &Grid,
+    data: &[(VectorID, Vec<f32>)],
+    metric: Metric,
+    mut f: impl FnMut(&str, String, &Build, &dyn Algorithm),
+) {
+    for algorithm in &grid.algorithms {
+        if !supports_metric(algorithm, metric) {
+            eprintln!("Skipping {algorithm}, which only supports the l2 metric");
+            continue;
+        }
+        match algorithm.as_str() {
+            "exact" => {
+                let (exact, build) = measure(|| Exact::with_metric(data, metric));
+                f("exact", String::new(), &build, &exact);
+            }
+            "kdtree" => {
+                for &leaf_size in &grid.kdtree_leaf_size {
+                    let config = KDTreeConfig {
+                        leaf_size,
+                        ..KDTreeConfig::default()
+                    };
+                    let (mut kdtree, build) = measure(|| KDTree::with_config(data, config));
+                    for &epsilon in &grid.kdtree_epsilon {
+                        kdtree.set_epsilon(epsilon);
+                        let params = format!("leaf_size={leaf_size} epsilon={epsilon}");
+                        f("kdtree", params, &build, &kdtree);
+                    }
+                }
+            }
+            "kdforest" => {
+                for &trees in &grid.kdforest_trees {
+                    let config = KDForestConfig {
+                        trees,
+                        ..KDForestConfig::default()
+                    };
+                    let (mut kdforest, build) = measure(|| KDForest::with_config(data, config));
+                    for &max_checks in &grid.kdforest_max_checks {
+                        kdforest.set_max_checks(max_checks);
+                        let params = format!("trees={trees} max_checks={max_checks}");
+                        f("kdforest", params, &build, &kdforest);
+                    }
+                }
+            }
+            "vptree" => {
+                for &leaf_size in &grid.vptree_leaf_size {
+                    for name in &grid.vptree_vantage {
+                        let vantage: Vantage = match name.parse() {
+                            Ok(vantage) => vantage,
+                            Err(e) => {
+                                eprintln!("Skipping {e}");
+                                continue;
+                            }
+                        };
+                        let config = VPTreeConfig {
+                            leaf_size,
+                            vantage,
+                            ..VPTreeConfig::default()
+                        };
+                        let (mut vptree, build) =
+                            measure(|| VPTree::with_metric(data, metric, config));
+                        for &epsilon in &grid.vptree_epsilon {
+                            for &max_visits in &grid.vptree_max_visits {
+                                vptree.set_epsilon(epsilon);
+                                vptree.set_max_visits(max_visits);
+                                let max_visits = match max_visits {
+                                    Some(max_visits) => max_visits.to_string(),
+                                    None => "all".to_owned(),
+                                };
+                                let params = format!(
+                                    "leaf_size={leaf_size} vantage={name} epsilon={epsilon} max_visits={max_visits}"
+                                );
+                                f("vptree", params, &build, &vptree);
+                            }
+                        }
+                    }
+                }
+            }
+            "mvptree" => {
+                for &order in &grid.mvptree_order {
+                    for &path_length in &grid.mvptree_path_length {
+                        let config = MvpTreeConfig {
+                            order,
+                            path_length,
+                            ..MvpTreeConfig::default()
+                        };
+                        let (mvptree, build) =
+                            measure(|| MvpTree::with_metric(data, metric, config));
+                        let params = format!("order={order} path_length={path_length}");
+                        f("mvptree", params, &build, &mvptree);
+                    }
+                }
+            }
+            "lsh" => {
+                for &tables in &grid.lsh_tables {
+                    for &bits_per_table in &grid.lsh_bits_per_table {
+                        let config = LSHConfig {
+                            tables,
+                            bits_per_table,
+                            ..LSHConfig::default()
+                        };
+                        let (mut lsh, build) = measure(|| LSH::with_metric(data, metric, config));
+                        for &candidate_factor in &grid.lsh_candidate_factor {
+                            for &probes in &grid.lsh_probes {
+                                lsh.set_candidate_factor(candidate_factor);
+                                lsh.set_probes(probes);
+                                let params = format!(
+                                    "tables={tables} bits_per_table={bits_per_table} candidate_factor={candidate_factor} probes={probes}"
+                                );
+                                f("lsh", params, &build, &lsh);
+                            }
+                        }
+                    }
+                }
+            }
+            "nsw" => {
+                for &neighbors in &grid.nsw_neighbors {
+                    for &build_attempts in &grid.nsw_build_attempts {
+                        let config = NSWConfig {
+                            neighbors,
+                            build_attempts,
+                            ..NSWConfig::default()
+                        };
+                        let (mut nsw, build) = measure(|| NSW::with_config(data, config));
+                        for &search_attempts in &grid.nsw_search_attempts {
+                            nsw.set_search_attempts(search_attempts);
+                            let params = format!(
+                                "neighbors={neighbors} build_attempts={build_attempts} search_attempts={search_attempts}"
+                            );
+                            f("nsw", params, &build, &nsw);
+                        }
+                    }
+                }
+            }
+            other => eprintln!("Skipping unknown algorithm {other:?}"),
+        }
+    }
+}
+
+fn measure<T>(build: impl FnOnce() -> T) -> (T, Build) {
+    let start = Instant::now();
+    let index = build();
+    let time = start.elapsed();
+    (index, Build { time })
+}
+
+/// Mark the points that no other point beats on both recall and QPS.
+pub fn mark_pareto(points: &mut [SweepPoint]) {
+    let dominated: Vec<bool> = points
+        .iter()
+        .map(|p| {
+            points.iter().any(|q| {
+                q.recall >= p.recall && q.qps >= p.qps && (q.recall > p.recall || q.qps > p.qps)
+            })
+        })
+        .collect();
+
+    for (point, dominated) in points.iter_mut().zip(dominated) {
+        point.pareto = !dominated;
+    }
+}
+
+pub fn write_csv(path: &str, points: &[SweepPoint]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(
+        writer,
+        "algorithm,params,build_time,memory,qps,recall,pareto"
+    )?;
+    for p in points {
+        writeln!(
+            writer,
+            "{},\"{}\",{:.6},{},{:.3},{:.6},{}",
+            p.algorithm, p.params, p.build_time, p.memory, p.qps, p.recall, p.pareto
+        )?;
+    }
+    writer.flush()
+}
+
+pub fn write_json(path: &str, points: &[SweepPoint]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, points)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(recall: f64, qps: f64) -> SweepPoint {
+        SweepPoint {
+            algorithm: String::new(),
+            params: String::new(),
+            build_time: 0.0,
+            memory: 0,
+            qps,
+            recall,
+            pareto: false,
+        }
+    }
+
+    #[test]
+    fn test_pareto() {
+        let mut points = vec![
+            point(1.0, 10.0),
+            point(0.9, 100.0),
+            point(0.8, 50.0),
+            point(0.5, 1000.0),
+            point(0.9, 90.0),
+        ];
+        mark_pareto(&mut points);
+        let pareto: Vec<bool> = points.iter().map(|p| p.pareto).collect();
+        assert_eq!(pareto, [true, true, false, true, false]);
+    }
+
+    #[test]
+    fn test_skips_indexes_without_the_metric() {
+        let data: Vec<(VectorID, Vec<f32>)> = (0..10).map(|i| (i, vec![i as f32, 1.0])).collect();
+        let grid = Grid {
+            algorithms: vec!["exact".to_owned(), "kdtree".to_owned(), "nsw".to_owned()],
+            ..Grid::default()
+        };
+
+        let mut built = Vec::new();
+        for_each_index(&grid, &data, Metric::Cosine, |algorithm, _, _, _| {
+            built.push(algorithm.to_owned())
+        });
+        assert_eq!(built, ["exact"]);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{env, f32::consts::TAU, str::FromStr};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{load_dataset, VectorID};
+
+/// Kinds of generated datasets. The same kind, size and seed always
+/// generates the same vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synthetic {
+    /// Uniform in the unit hypercube
+    Uniform,
+    /// Gaussian blobs around uniformly placed centers
+    Clusters,
+    /// Uniform on the surface of the unit sphere
+    Sphere,
+    /// A curved manifold with an intrinsic dimension of `min(4, dim)`
+    Manifold,
+    /// Few distinct vectors, each repeated many times
+    Duplicates,
+}
+
+impl Synthetic {
+    pub const ALL: [Synthetic; 5] = [
+        Synthetic::Uniform,
+        Synthetic::Clusters,
+        Synthetic::Sphere,
+        Synthetic::Manifold,
+        Synthetic::Duplicates,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Synthetic::Uniform => "uniform",
+            Synthetic::Clusters => "clusters",
+            Synthetic::Sphere => "sphere",
+            Synthetic::Manifold => "manifold",
+            Synthetic::Duplicates => "duplicates",
+        }
+    }
+
+    pub fn generate(&self, n: usize, dim: usize, seed: u64) -> Vec<(VectorID, Vec<f32>)> {
+        match self {
+            Synthetic::Uniform => uniform(n, dim, seed),
+            Synthetic::Clusters => gaussian_clusters(n, dim, (n / 100).clamp(1, 64), 0.05, seed),
+            Synthetic::Sphere => unit_sphere(n, dim, seed),
+            Synthetic::Manifold => manifold(n, dim, dim.min(4), seed),
+            Synthetic::Duplicates => duplicates(n, dim, (n / 20).max(1), seed),
+        }
+    }
+}
+
+impl FromStr for Synthetic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Synthetic::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| format!("unknown synthetic dataset {s:?}"))
+    }
+}
+
+/// The dataset at `DATASET_PATH` if it is set, otherwise a generated one.
+pub fn load_env_or_generate(
+    kind: Synthetic,
+    n: usize,
+    dim: usize,
+    seed: u64,
+) -> Vec<(VectorID, Vec<f32>)> {
+    match env::var("DATASET_PATH") {
+        Ok(path) => load_dataset(&path)
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (_str, vector))| (idx, vector))
+            .collect(),
+        Err(_) => kind.generate(n, dim, seed),
+    }
+}
+
+pub fn uniform(n: usize, dim: usize, seed: u64) -> Vec<(VectorID, Vec<f32>)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n)
+        .map(|id| (id, (0..dim).map(|_| rng.gen::<f32>()).collect()))
+        .collect()
+}
+
+/// `clusters` isotropic Gaussian blobs with standard deviation `spread`,
+/// centered uniformly in the unit hypercube.
+pub fn gaussian_clusters(
+    n: usize,
+    dim: usize,
+    clusters: usize,
+    spread: f32,
+    seed: u64,
+) -> Vec<(VectorID, Vec<f32>)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let centers: Vec<Vec<f32>> = (0..clusters)
+        .map(|_| (0..dim).map(|_| rng.gen::<f32>()).collect())
+        .collect();
+
+    (0..n)
+        .map(|id| {
+            let center = &centers[rng.gen_range(0..clusters)];
+            let vector = center
+                .iter()
+                .map(|c| c + spread * gaussian(&mut rng))
+                .collect();
+            (id, vector)
+        })
+        .collect()
+}
+
+pub fn unit_sphere(n: usize, dim: usize, seed: u64) -> Vec<(VectorID, Vec<f32>)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n)
+        .map(|id| {
+            let mut vector: Vec<f32> = (0..dim).map(|_| gaussian(&mut rng)).collect();
+            let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                vector.iter_mut().for_each(|x| *x /= norm);
+            }
+            (id, vector)
+        })
+        .collect()
+}
+
+/// Points with `intrinsic_dim` degrees of freedom embedded in `dim`
+/// dimensions by a fixed random projection followed by a sine, so the
+/// manifold is curved rather than a flat subspace.
+pub fn manifold(
+    n: usize,
+    dim: usize,
+    intrinsic_dim: usize,
+    seed: u64,
+) -> Vec<(VectorID, Vec<f32>)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let projection: Vec<Vec<f32>> = (0..dim)
+        .map(|_| (0..intrinsic_dim).map(|_| gaussian(&mut rng)).collect())
+        .collect();
+
+    (0..n)
+        .map(|id| {
+            let latent: Vec<f32> = (0..intrinsic_dim).map(|_| rng.gen::<f32>()).collect();
+            let vector = projection
+                .iter()
+                .map(|row| {
+                    let x: f32 = row.iter().zip(&latent).map(|(a, z)| a * z).sum();
+                    x.sin()
+                })
+                .collect();
+            (id, vector)
+        })
+        .collect()
+}
+
+/// `distinct` uniform vectors, each repeated exactly, in shuffled order. Ties
+/// everywhere make this a worst case for splitting and hashing.
+pub fn duplicates(n: usize, dim: usize, distinct: usize, seed: u64) -> Vec<(VectorID, Vec<f32>)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let originals = uniform(distinct, dim, seed);
+
+    let mut vectors: Vec<Vec<f32>> = (0..n).map(|i| originals[i % distinct].1.clone()).collect();
+    vectors.shuffle(&mut rng);
+
+    vectors.into_iter().enumerate().collect()
+}
+
+/// Standard normal sample using the Box-Muller transform.
+fn gaussian(rng: &mut impl Rng) -> f32 {
+    let u1: f32 = 1.0 - rng.gen::<f32>(); // (0, 1] so ln is finite
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (TAU * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generators() {
+        for kind in Synthetic::ALL {
+            let data = kind.generate(500, 8, 42);
+            assert_eq!(data.len(), 500);
+            assert!(data.iter().enumerate().all(|(idx, (id, _))| idx == *id));
+            assert!(data
+                .iter()
+                .all(|(_, v)| v.len() == 8 && v.iter().all(|x| x.is_finite())));
+            assert_eq!(data, kind.generate(500, 8, 42));
+            assert_eq!(kind.name().parse(), Ok(kind));
+        }
+
+        let sphere = unit_sphere(10, 3, 0);
+        assert!(sphere
+            .iter()
+            .all(|(_, v)| (v.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-5));
+    }
+}
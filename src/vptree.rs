@@ -1,129 +1,545 @@
 use core::f32;
-use std::f32::NAN;
 
-use crate::{distance, Algorithm, BinaryTree, OrdItem, LimitedHeap, Node, VectorID};
+use std::{mem::size_of, ops::Range, str::FromStr};
+
+use crate::{
+    memory::{vec_bytes, MemoryUsage},
+    stats::{SearchStats, Stats},
+    Algorithm, BinaryTree, LimitedHeap, Metric, Node, OrdItem, VectorID, Vectors,
+};
 use rand::Rng;
 
-pub struct VPTree {
+/// Vantage point tree over any points with a distance that satisfies the
+/// triangle inequality, which its pruning relies on. Vectors are stored in
+/// `Vectors`, other points in a `Vec`.
+pub struct VPTree<P: Points = Vectors, M = Metric> {
     tree: BinaryTree<TreeItem>,
+    points: P,
+    /// id of the point in each row
+    ids: Vec<VectorID>,
+    metric: M,
+    epsilon: f32,
+    max_visits: Option<usize>,
+}
+
+/// Distance between two points. Must satisfy the triangle inequality for
+/// searches to be exact.
+pub trait Distance<T: ?Sized> {
+    fn distance(&self, x: &T, y: &T) -> f32;
+}
+
+impl Distance<[f32]> for Metric {
+    fn distance(&self, x: &[f32], y: &[f32]) -> f32 {
+        Metric::distance(self, x, y)
+    }
+}
+
+impl<T: ?Sized, F: Fn(&T, &T) -> f32> Distance<T> for F {
+    fn distance(&self, x: &T, y: &T) -> f32 {
+        self(x, y)
+    }
+}
+
+/// Storage for the points of a tree, row by row
+pub trait Points {
+    type Point: ?Sized;
+
+    fn push(&mut self, point: &Self::Point);
+    fn row(&self, row: usize) -> &Self::Point;
+    /// Heap bytes of the storage
+    fn bytes(&self) -> usize;
+}
+
+impl Points for Vectors {
+    type Point = [f32];
+
+    fn push(&mut self, point: &[f32]) {
+        Vectors::push(self, point);
+    }
+
+    fn row(&self, row: usize) -> &[f32] {
+        Vectors::row(self, row)
+    }
+
+    fn bytes(&self) -> usize {
+        Vectors::bytes(self)
+    }
+}
+
+/// Heap memory owned by the points themselves isn't counted.
+impl<T: Clone> Points for Vec<T> {
+    type Point = T;
+
+    fn push(&mut self, point: &T) {
+        Vec::push(self, point.clone());
+    }
+
+    fn row(&self, row: usize) -> &T {
+        &self[row]
+    }
+
+    fn bytes(&self) -> usize {
+        vec_bytes(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VPTreeConfig {
+    /// max no. of points in a leaf, which are scanned by brute force
+    pub leaf_size: usize,
+    pub vantage: Vantage,
+    /// no. of candidates and of points they're compared to, for
+    /// `Vantage::MaxSpread`
+    pub sample_size: usize,
+    /// searches may skip branches as long as every neighbor they return is
+    /// within (1 + epsilon) times the distance of the true one. 0 is exact.
+    pub epsilon: f32,
+    /// no. of nodes after which a search stops, once it has found k
+    /// neighbors. `None` searches until no branch is left.
+    pub max_visits: Option<usize>,
+}
+
+impl Default for VPTreeConfig {
+    fn default() -> Self {
+        Self {
+            leaf_size: 16,
+            vantage: Vantage::Random,
+            sample_size: 16,
+            epsilon: 0.0,
+            max_visits: None,
+        }
+    }
+}
+
+/// How the vantage point of every node is chosen from its points
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vantage {
+    /// uniformly at random
+    Random,
+    /// the candidate of a random sample whose distances to a second sample
+    /// spread the most around their median (Yianilos)
+    MaxSpread,
+    /// the point farthest from a random point, which tends to be a corner
+    /// of the data
+    Farthest,
+}
+
+impl Vantage {
+    pub const ALL: [Vantage; 3] = [Vantage::Random, Vantage::MaxSpread, Vantage::Farthest];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Vantage::Random => "random",
+            Vantage::MaxSpread => "spread",
+            Vantage::Farthest => "farthest",
+        }
+    }
+}
+
+impl FromStr for Vantage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Vantage::ALL
+            .into_iter()
+            .find(|vantage| vantage.name() == s)
+            .ok_or_else(|| format!("unknown vantage point selection {s:?}"))
+    }
+}
+
+/// A point, its id and its distance to the vantage point of the node being
+/// built
+type Item<'a, T> = (f32, VectorID, &'a T);
+
+#[derive(Debug, Clone)]
+enum TreeItem {
+    /// a vantage point's row and the median distance of the points below it
+    Vantage {
+        mu: f32,
+        row: u32,
+    },
+    Leaf {
+        start: u32,
+        end: u32,
+    },
 }
 
 impl Algorithm for VPTree {
     fn search(&self, query: &[f32], k: usize) -> Vec<VectorID> {
-        self.nearest_neighbors(query, k)
+        self.nearest_neighbors(query, k, &mut ())
+            .into_iter()
+            .map(|OrdItem(_, id)| id)
+            .collect()
+    }
+
+    fn search_with_stats(&self, query: &[f32], k: usize) -> (Vec<VectorID>, SearchStats) {
+        let mut stats = SearchStats::default();
+        let result = self.nearest_neighbors(query, k, &mut stats);
+        (result.into_iter().map(|OrdItem(_, id)| id).collect(), stats)
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            vectors: self.points.bytes() + vec_bytes(&self.ids),
+            nodes: self.tree.nodes.capacity() * size_of::<Node<TreeItem>>(),
+            ..Default::default()
+        }
     }
 }
 
 impl VPTree {
     pub fn load(data: &[(VectorID, Vec<f32>)]) -> Self {
-        let points: Vec<(Vec<f32>, VectorID)> = data
+        Self::with_config(data, VPTreeConfig::default())
+    }
+
+    pub fn with_config(data: &[(VectorID, Vec<f32>)], config: VPTreeConfig) -> Self {
+        Self::with_metric(data, Metric::SquaredEuclidean, config)
+    }
+
+    /// Neighbors are ranked by `metric`. Squared L2 and cosine distance
+    /// don't satisfy the triangle inequality, so the tree is built on
+    /// Euclidean and angular distance instead, which rank the same.
+    pub fn with_metric(
+        data: &[(VectorID, Vec<f32>)],
+        metric: Metric,
+        config: VPTreeConfig,
+    ) -> Self {
+        let dimensions = data.first().map_or(0, |(_, v)| v.len());
+        let points = Vectors::with_capacity(dimensions, data.len());
+        let mut items: Vec<Item<[f32]>> = data
             .iter()
-            .map(|(id, vector)| (vector.clone(), *id))
+            .map(|(id, v)| (f32::NAN, *id, v.as_slice()))
             .collect();
-        Self {
-            tree: Self::build(points),
-        }
+        Self::build_tree(points, metric.true_metric(), &mut items, config)
+    }
+}
+
+impl<T: Clone, M: Distance<T>> VPTree<Vec<T>, M> {
+    /// Index arbitrary points, such as strings under edit distance or sets
+    /// under Jaccard distance.
+    pub fn with_distance(data: &[(VectorID, T)], metric: M, config: VPTreeConfig) -> Self {
+        let mut items: Vec<Item<T>> = data.iter().map(|(id, t)| (f32::NAN, *id, t)).collect();
+        Self::build_tree(Vec::with_capacity(data.len()), metric, &mut items, config)
+    }
+}
+
+impl<P: Points, M: Distance<P::Point>> VPTree<P, M> {
+    fn build_tree(
+        points: P,
+        metric: M,
+        items: &mut [Item<P::Point>],
+        config: VPTreeConfig,
+    ) -> Self {
+        let mut s = Self {
+            tree: BinaryTree::with_capacity(2 * items.len() / config.leaf_size.max(1)),
+            points,
+            ids: Vec::with_capacity(items.len()),
+            metric,
+            epsilon: config.epsilon,
+            max_visits: config.max_visits,
+        };
+        s.build(items, &config, &mut rand::thread_rng());
+        s
+    }
+
+    /// Changing epsilon doesn't require rebuilding the tree.
+    pub fn set_epsilon(&mut self, epsilon: f32) {
+        self.epsilon = epsilon;
+    }
+
+    /// Changing the visit budget doesn't require rebuilding the tree.
+    pub fn set_max_visits(&mut self, max_visits: Option<usize>) {
+        self.max_visits = max_visits;
+    }
+
+    /// The k nearest neighbors and their distances, closest first
+    pub fn search_with_distances(&self, query: &P::Point, k: usize) -> Vec<OrdItem<VectorID>> {
+        self.nearest_neighbors(query, k, &mut ())
     }
 
-    fn build(mut points: Vec<(Vec<f32>, VectorID)>) -> BinaryTree<TreeItem> {
-        if points.is_empty() {
-            return BinaryTree(None);
+    /// Nodes and rows are both added in pre-order, so that a subtree's
+    /// points are next to each other in memory.
+    fn build(
+        &mut self,
+        items: &mut [Item<P::Point>],
+        config: &VPTreeConfig,
+        rng: &mut impl Rng,
+    ) -> Option<usize> {
+        if items.is_empty() {
+            return None;
         }
 
-        let select_vp = |size: usize| -> usize { rand::thread_rng().gen_range(0..size) };
+        if items.len() <= config.leaf_size.max(1) {
+            let start = self.ids.len() as u32;
+            for (_, id, point) in items.iter() {
+                self.points.push(point);
+                self.ids.push(*id);
+            }
+            let end = self.ids.len() as u32;
+            return Some(self.tree.push(TreeItem::Leaf { start, end }));
+        }
 
-        let vantage_pt = points.swap_remove(select_vp(points.len()));
+        let last = items.len() - 1;
+        let vantage = self.select_vantage(items, config, rng);
+        items.swap(vantage, last);
+        let ((_, vp_id, vantage_pt), items) = items.split_last_mut().unwrap();
 
-        if points.is_empty() {
-            return BinaryTree(Some(Box::new(Node {
-                value: TreeItem(NAN, vantage_pt.0, vantage_pt.1),
-                left: BinaryTree(None),
-                right: BinaryTree(None),
-            })));
+        let row = self.ids.len() as u32;
+        self.points.push(vantage_pt);
+        self.ids.push(*vp_id);
+
+        for item in items.iter_mut() {
+            item.0 = self.metric.distance(vantage_pt, item.2);
         }
 
-        let mut points_with_dist: Vec<TreeItem> = points
-            .into_iter()
-            .map(|(vector, id)| TreeItem(distance(&vantage_pt.0, &vector), vector, id))
-            .collect();
+        // the points up to the median are inside the circle and the rest are
+        // on or outside it, partitioned in place and in linear time
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |a, b| a.0.total_cmp(&b.0));
+        let mu = items[mid].0;
+        let node = self.tree.push(TreeItem::Vantage { mu, row });
+        let (left, right) = items.split_at_mut(mid);
 
-        let median = |v: &[TreeItem]| -> f32 {
-            if v.is_empty() {
-                return NAN;
-            }
-            if v.len() % 2 == 0 {
-                (v[(v.len() / 2) - 1].0 + v[v.len() / 2].0) / 2.0
-            } else {
-                v[v.len() / 2].0
-            }
-        };
+        let left = self.build(left, config, rng);
+        let right = self.build(right, config, rng);
+        self.tree.set_children(node, left, right);
 
-        points_with_dist.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-        let mu = median(&points_with_dist);
+        Some(node)
+    }
 
-        let (left, right): (Vec<TreeItem>, Vec<TreeItem>) =
-            points_with_dist.into_iter().partition(|pt| pt.0 < mu);
+    /// Index of the vantage point among `items`
+    fn select_vantage(
+        &self,
+        items: &[Item<P::Point>],
+        config: &VPTreeConfig,
+        rng: &mut impl Rng,
+    ) -> usize {
+        let random = rng.gen_range(0..items.len());
+        match config.vantage {
+            Vantage::Random => random,
+            Vantage::Farthest => {
+                let origin = items[random].2;
+                let mut farthest = (f32::NEG_INFINITY, random);
+                for (i, item) in items.iter().enumerate() {
+                    let d = self.metric.distance(origin, item.2);
+                    if d > farthest.0 {
+                        farthest = (d, i);
+                    }
+                }
+                farthest.1
+            }
+            Vantage::MaxSpread => {
+                let size = config.sample_size.clamp(1, items.len());
+                let candidates = rand::seq::index::sample(rng, items.len(), size);
+                let sample = rand::seq::index::sample(rng, items.len(), size);
 
-        BinaryTree(Some(Box::new(Node {
-            value: TreeItem(mu, vantage_pt.0, vantage_pt.1),
-            left: Self::build(left.iter().map(|x| (x.1.clone(), x.2)).collect()),
-            right: Self::build(right.iter().map(|x| (x.1.clone(), x.2)).collect()),
-        })))
+                let mut best = (f32::NEG_INFINITY, random);
+                let mut distances = Vec::with_capacity(size);
+                for candidate in candidates {
+                    distances.clear();
+                    distances.extend(
+                        sample
+                            .iter()
+                            .map(|i| self.metric.distance(items[candidate].2, items[i].2)),
+                    );
+                    let mid = distances.len() / 2;
+                    let median = *distances.select_nth_unstable_by(mid, f32::total_cmp).1;
+                    let spread: f32 = distances.iter().map(|d| (d - median).powi(2)).sum();
+                    if spread > best.0 {
+                        best = (spread, candidate);
+                    }
+                }
+                best.1
+            }
+        }
     }
 
-    fn nearest_neighbors(&self, target: &[f32], k: usize) -> Vec<VectorID> {
+    fn nearest_neighbors(
+        &self,
+        target: &P::Point,
+        k: usize,
+        stats: &mut impl Stats,
+    ) -> Vec<OrdItem<VectorID>> {
+        if k == 0 {
+            return vec![];
+        }
+
         let mut tau = f32::INFINITY; // threshold distance for target
+        let slack = 1.0 + self.epsilon;
 
-        let mut stack = vec![&self.tree];
-        let mut neighbors: LimitedHeap<OrdItem<(&[f32], VectorID)>> = LimitedHeap::new(k);
+        let mut stack: Vec<usize> = self.tree.root().into_iter().collect();
+        let mut neighbors: LimitedHeap<OrdItem<VectorID>> = LimitedHeap::new(k);
+        let mut visits = 0;
 
         while let Some(node) = stack.pop() {
-            let Some(node) = &node.0 else {
-                continue;
-            };
-
-            let d = distance(target, &node.value.1);
-
-            if d < tau {
-                neighbors.push(OrdItem(d, (&node.value.1, node.value.2)));
-                let OrdItem(_, farthest_nearest_neighbor) = neighbors.peek().unwrap();
-                tau = distance(target, farthest_nearest_neighbor.0);
+            if neighbors.is_full() && self.max_visits.is_some_and(|max| visits >= max) {
+                stats.prune();
+                break;
             }
+            let node = &self.tree[node];
+            visits += 1;
+            stats.visit();
 
-            if node.left.0.is_none() && node.right.0.is_none() {
-                // i.e. if is leaf
-                continue;
-            }
+            // mu is the division boundary for the vantage point
+            let (mu, row) = match node.value {
+                TreeItem::Leaf { start, end } => {
+                    let rows = start as usize..end as usize;
+                    self.scan(rows, target, &mut neighbors, &mut tau, stats);
+                    continue;
+                }
+                TreeItem::Vantage { mu, row } => (mu, row as usize),
+            };
 
-            let mu = node.value.0; // division boundary for current vantage point
+            let d = self.scan(row..row + 1, target, &mut neighbors, &mut tau, stats);
 
-            // inside circle
+            // only points closer than tau / slack are worth looking for
+            let radius = tau / slack;
+            let visit_left = d < mu + radius;
+            let visit_right = d >= mu - radius;
+
+            // the side the target is on is pushed last so that it's searched
+            // first, which reduces tau earlier and prevents us from exploring
+            // unnecessary branches
             if d < mu {
-                if d < mu + tau {
-                    stack.push(&node.left);
+                // inside circle
+                if visit_right {
+                    stack.extend(node.right());
                 }
-                if d >= mu - tau {
-                    stack.push(&node.right);
+                if visit_left {
+                    stack.extend(node.left());
                 }
             } else {
-                // the order is important because it'll reduce tau earlier
-                // and prevent us from exploring unnecessary branches
-                if d >= mu - tau {
-                    stack.push(&node.right);
+                if visit_left {
+                    stack.extend(node.left());
                 }
-                if d < mu + tau {
-                    stack.push(&node.left);
+                if visit_right {
+                    stack.extend(node.right());
                 }
             };
+
+            for (visited, branch) in [(visit_left, node.left()), (visit_right, node.right())] {
+                if !visited && branch.is_some() {
+                    stats.prune();
+                }
+            }
         }
 
-        let mut v: Vec<&OrdItem<(&[f32], VectorID)>> = neighbors.iter().collect();
-        v.sort();
-        v.into_iter()
-            .map(|&OrdItem(_, (_vector, id))| id)
-            .collect()
+        neighbors.consume().into_sorted_vec()
+    }
+
+    /// Add the rows closer than `tau` to the neighbors, shrinking `tau` once
+    /// there are k of them, and return the distance to the last row.
+    fn scan(
+        &self,
+        rows: Range<usize>,
+        target: &P::Point,
+        neighbors: &mut LimitedHeap<OrdItem<VectorID>>,
+        tau: &mut f32,
+        stats: &mut impl Stats,
+    ) -> f32 {
+        let mut d = f32::NAN;
+        for row in rows {
+            stats.distance();
+            d = self.metric.distance(target, self.points.row(row));
+
+            if d < *tau {
+                neighbors.push(OrdItem(d, self.ids[row]));
+                // only a full heap bounds the distance of the k-th neighbor
+                if neighbors.is_full() {
+                    *tau = neighbors.peek().unwrap().0;
+                }
+            }
+        }
+        d
     }
 }
 
-#[derive(Debug, Clone)]
-struct TreeItem(f32, Vec<f32>, VectorID);
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn edit_distance(a: &str, b: &str) -> f32 {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, x) in a.chars().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, y) in b.iter().enumerate() {
+                let substitute = diagonal + (x != *y) as usize;
+                diagonal = row[j + 1];
+                row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+            }
+        }
+        row[b.len()] as f32
+    }
+
+    fn jaccard_distance(a: &BTreeSet<u32>, b: &BTreeSet<u32>) -> f32 {
+        let union = a.union(b).count();
+        if union == 0 {
+            return 0.0;
+        }
+        1.0 - a.intersection(b).count() as f32 / union as f32
+    }
+
+    /// Distances of the k nearest points by brute force
+    fn brute_force<T>(
+        data: &[(VectorID, T)],
+        query: &T,
+        k: usize,
+        metric: impl Fn(&T, &T) -> f32,
+    ) -> Vec<f32> {
+        let mut distances: Vec<f32> = data.iter().map(|(_, t)| metric(query, t)).collect();
+        distances.sort_by(f32::total_cmp);
+        distances.truncate(k);
+        distances
+    }
+
+    #[test]
+    fn test_strings_by_edit_distance() {
+        let mut rng = rand::thread_rng();
+        let words: Vec<(VectorID, String)> = (0..500)
+            .map(|id| {
+                let len = rng.gen_range(3..10);
+                (id, (0..len).map(|_| rng.gen_range('a'..='e')).collect())
+            })
+            .collect();
+
+        let metric = |a: &String, b: &String| edit_distance(a, b);
+
+        for leaf_size in [1, 16] {
+            let config = VPTreeConfig {
+                leaf_size,
+                ..VPTreeConfig::default()
+            };
+            let tree = VPTree::with_distance(&words, metric, config);
+            for (_, query) in words.iter().step_by(50) {
+                let found: Vec<f32> = tree
+                    .search_with_distances(query, 10)
+                    .iter()
+                    .map(|item| item.0)
+                    .collect();
+                assert_eq!(found, brute_force(&words, query, 10, metric));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sets_by_jaccard_distance() {
+        let mut rng = rand::thread_rng();
+        let sets: Vec<(VectorID, BTreeSet<u32>)> = (0..500)
+            .map(|id| (id, (0..8).map(|_| rng.gen_range(0..30)).collect()))
+            .collect();
+
+        let tree = VPTree::with_distance(&sets, jaccard_distance, VPTreeConfig::default());
+        for (_, query) in sets.iter().step_by(50) {
+            let found: Vec<f32> = tree
+                .search_with_distances(query, 10)
+                .iter()
+                .map(|item| item.0)
+                .collect();
+            assert_eq!(found, brute_force(&sets, query, 10, jaccard_distance));
+        }
+    }
+}
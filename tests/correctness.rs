@@ -0,0 +1,481 @@
+use std::collections::{HashMap, HashSet};
+
+use nearest_neighbors::{
+    distance, eval,
+    exact::Exact,
+    groundtruth::GroundTruth,
+    kdforest::{KDForest, KDForestConfig},
+    kdtree::{KDTree, KDTreeConfig},
+    lsh::{LSHConfig, LSH},
+    mvptree::{MvpTree, MvpTreeConfig},
+    nsw::NSW,
+    synthetic::{self, Synthetic},
+    vptree::{VPTree, VPTreeConfig, Vantage},
+    Algorithm, Metric, VectorID,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const METRIC: Metric = Metric::SquaredEuclidean;
+
+type Dataset = Vec<(VectorID, Vec<f32>)>;
+
+/// Indexes that must return exactly what brute force returns.
+fn exact_indexes(data: &Dataset) -> Vec<(&'static str, Box<dyn Algorithm>)> {
+    vec![
+        ("kdtree", Box::new(KDTree::load(data))),
+        (
+            "kdtree leaf_size=1",
+            Box::new(KDTree::with_config(
+                data,
+                KDTreeConfig {
+                    leaf_size: 1,
+                    ..KDTreeConfig::default()
+                },
+            )),
+        ),
+        (
+            "kdforest max_checks=n",
+            Box::new(KDForest::with_config(
+                data,
+                KDForestConfig {
+                    max_checks: data.len(),
+                    ..KDForestConfig::default()
+                },
+            )),
+        ),
+        ("vptree", Box::new(VPTree::load(data))),
+        (
+            "vptree leaf_size=1",
+            Box::new(VPTree::with_config(
+                data,
+                VPTreeConfig {
+                    leaf_size: 1,
+                    ..VPTreeConfig::default()
+                },
+            )),
+        ),
+        (
+            "vptree vantage=spread",
+            Box::new(VPTree::with_config(
+                data,
+                VPTreeConfig {
+                    vantage: Vantage::MaxSpread,
+                    ..VPTreeConfig::default()
+                },
+            )),
+        ),
+        (
+            "vptree vantage=farthest",
+            Box::new(VPTree::with_config(
+                data,
+                VPTreeConfig {
+                    vantage: Vantage::Farthest,
+                    ..VPTreeConfig::default()
+                },
+            )),
+        ),
+        ("mvptree", Box::new(MvpTree::load(data))),
+        (
+            "mvptree order=2 leaf_size=2",
+            Box::new(MvpTree::with_config(
+                data,
+                MvpTreeConfig {
+                    order: 2,
+                    leaf_size: 2,
+                    ..MvpTreeConfig::default()
+                },
+            )),
+        ),
+    ]
+}
+
+/// Approximate indexes and the minimum mean recall@10 they must reach on
+/// every dataset. All are randomized, so the floors leave some margin.
+fn approximate_indexes(data: &Dataset) -> Vec<(&'static str, Box<dyn Algorithm>, f64)> {
+    vec![
+        ("kdforest", Box::new(KDForest::load(data)), 0.5),
+        ("lsh", Box::new(LSH::load(data)), 0.3),
+        ("nsw", Box::new(NSW::load(data)), 0.5),
+        (
+            "vptree max_visits=64",
+            Box::new(VPTree::with_config(
+                data,
+                VPTreeConfig {
+                    max_visits: Some(64),
+                    ..VPTreeConfig::default()
+                },
+            )),
+            0.5,
+        ),
+    ]
+}
+
+fn datasets() -> Vec<(String, Dataset)> {
+    let mut datasets = Vec::new();
+    for kind in Synthetic::ALL {
+        for (n, dim) in [(1000, 2), (1000, 8), (500, 32)] {
+            datasets.push((
+                format!("{}-{}x{}", kind.name(), n, dim),
+                kind.generate(n, dim, 7),
+            ));
+        }
+    }
+    datasets
+}
+
+/// Half of the queries are indexed points, the other half are new points
+/// from the same distribution.
+fn queries(kind_data: &Dataset, fresh: &Dataset) -> Vec<Vec<f32>> {
+    kind_data
+        .iter()
+        .step_by(kind_data.len() / 10 + 1)
+        .chain(fresh.iter().take(10))
+        .map(|(_, v)| v.clone())
+        .collect()
+}
+
+/// Compare against brute force by distance so that ties between equally
+/// distant points may be broken either way.
+fn assert_same_as_exact(
+    name: &str,
+    dataset: &str,
+    data: &Dataset,
+    index: &dyn Algorithm,
+    query: &[f32],
+    k: usize,
+) {
+    let exact = Exact::with_metric(data, METRIC);
+    let expected: Vec<f32> = exact
+        .search_with_distances(query, k)
+        .iter()
+        .map(|item| item.0)
+        .collect();
+
+    let result = index.search(query, k);
+    let distances: Vec<f32> = result
+        .iter()
+        .map(|id| distance(query, &data[*id].1))
+        .collect();
+
+    let mut unique = result.clone();
+    unique.sort();
+    unique.dedup();
+
+    assert_eq!(
+        unique.len(),
+        result.len(),
+        "{name} on {dataset} returned duplicate ids for k = {k}"
+    );
+    assert_eq!(
+        distances, expected,
+        "{name} on {dataset} differs from brute force for k = {k}"
+    );
+}
+
+#[test]
+fn test_exact_indexes_match_brute_force() {
+    for (dataset, data) in datasets() {
+        let fresh = synthetic::uniform(10, data[0].1.len(), 99);
+        for (name, index) in exact_indexes(&data) {
+            for query in queries(&data, &fresh) {
+                for k in [1, 5, 10, 50] {
+                    assert_same_as_exact(name, &dataset, &data, index.as_ref(), &query, k);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_approximate_indexes_meet_recall_floor() {
+    for (dataset, data) in datasets() {
+        let kind: Synthetic = dataset.split('-').next().unwrap().parse().unwrap();
+        let fresh = kind.generate(10, data[0].1.len(), 99);
+        let queries = queries(&data, &fresh);
+        let ground_truth = GroundTruth::compute(&data, &queries, 10, METRIC);
+
+        for (name, index, floor) in approximate_indexes(&data) {
+            let evaluation =
+                eval::evaluate(index.as_ref(), &data, &queries, &ground_truth, 10, METRIC);
+            assert!(
+                evaluation.recall >= floor,
+                "{name} on {dataset}: recall@10 {} is below {floor}",
+                evaluation.recall
+            );
+        }
+    }
+}
+
+#[test]
+fn test_edge_cases() {
+    let data = synthetic::uniform(100, 4, 3);
+    let single = synthetic::uniform(1, 4, 3);
+    let query = vec![0.5; 4];
+
+    let all_indexes = |data: &Dataset| -> Vec<(&'static str, Box<dyn Algorithm>)> {
+        let mut indexes = exact_indexes(data);
+        indexes.extend(
+            approximate_indexes(data)
+                .into_iter()
+                .map(|(name, index, _)| (name, index)),
+        );
+        indexes
+    };
+
+    for (name, index) in all_indexes(&data) {
+        assert!(index.search(&query, 0).is_empty(), "{name} with k = 0");
+        assert!(index.search(&query, 100).len() <= 100, "{name} with k = n");
+    }
+
+    for (name, index) in exact_indexes(&data) {
+        assert_same_as_exact(name, "uniform", &data, index.as_ref(), &query, 150);
+        assert_eq!(index.search(&query, 150).len(), 100, "{name} with k > n");
+
+        // a query identical to an indexed point finds that point first
+        assert_eq!(index.search(&data[42].1, 1), [42], "{name} on itself");
+    }
+
+    let empty = Dataset::new();
+    for (name, index) in all_indexes(&empty) {
+        assert!(index.search(&query, 5).is_empty(), "{name} with no points");
+    }
+
+    for (name, index) in all_indexes(&single) {
+        assert_eq!(index.search(&query, 1), [0], "{name} with one point");
+        assert_eq!(index.search(&query, 5), [0], "{name} with one point, k > n");
+    }
+}
+
+#[test]
+fn test_duplicate_vectors() {
+    // every vector appears 20 times, so any k <= 20 nearest are all at distance 0
+    let data = synthetic::duplicates(400, 8, 20, 5);
+
+    for (name, index) in exact_indexes(&data) {
+        for (_, vector) in data.iter().take(20) {
+            assert_same_as_exact(name, "duplicates", &data, index.as_ref(), vector, 20);
+            assert_same_as_exact(name, "duplicates", &data, index.as_ref(), vector, 30);
+        }
+    }
+}
+
+#[test]
+fn test_lsh_tables() {
+    let data = synthetic::gaussian_clusters(1000, 8, 10, 0.2, 23);
+    let queries = synthetic::gaussian_clusters(10, 8, 10, 0.2, 24);
+
+    // with 300 bits every point has a bucket of its own, so the probe budget
+    // runs out before there are k candidates
+    for (tables, bits_per_table) in [(1, 8), (16, 8), (2, 300)] {
+        let config = LSHConfig {
+            tables,
+            bits_per_table,
+            ..LSHConfig::default()
+        };
+        let lsh = LSH::with_config(&data, config);
+        for (_, query) in &queries {
+            let (result, stats) = lsh.search_with_stats(query, 50);
+            assert!(
+                stats.buckets_probed <= tables * config.probes,
+                "{tables} x {bits_per_table} bits: {stats}"
+            );
+            if bits_per_table == 8 {
+                assert_eq!(result.len(), 50, "{tables} x {bits_per_table} bits");
+            }
+
+            // a point is in the query's bucket of many tables at once
+            let unique: HashSet<&VectorID> = result.iter().collect();
+            assert_eq!(
+                unique.len(),
+                result.len(),
+                "{tables} x {bits_per_table} bits returned duplicate ids"
+            );
+
+            // candidates are ranked by their true distance
+            let distances: Vec<f32> = result
+                .iter()
+                .map(|id| distance(query, &data[*id].1))
+                .collect();
+            assert!(
+                distances.is_sorted(),
+                "{tables} x {bits_per_table} bits: {distances:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_search_stats() {
+    let data = synthetic::uniform(2000, 2, 11);
+    let query = vec![0.3, 0.6];
+
+    for (name, index) in exact_indexes(&data) {
+        let (result, stats) = index.search_with_stats(&query, 10);
+        assert_eq!(result, index.search(&query, 10), "{name} with stats");
+        // low dimensional data should let the trees skip most of the points
+        assert!(
+            stats.distance_computations < data.len() / 5,
+            "{name}: {stats}"
+        );
+        assert!(stats.branches_pruned > 0, "{name}: {stats}");
+    }
+}
+
+#[test]
+fn test_metric_trees_with_other_metrics() {
+    let data = synthetic::gaussian_clusters(1000, 8, 10, 0.2, 21);
+    let fresh = synthetic::gaussian_clusters(10, 8, 10, 0.2, 22);
+
+    for metric in [
+        Metric::SquaredEuclidean,
+        Metric::Euclidean,
+        Metric::Cosine,
+        Metric::Angular,
+        Metric::Manhattan,
+    ] {
+        let exact = Exact::with_metric(&data, metric);
+        let vptree = VPTree::with_metric(&data, metric, VPTreeConfig::default());
+        let mvptree = MvpTree::with_metric(&data, metric, MvpTreeConfig::default());
+
+        for query in queries(&data, &fresh) {
+            let expected: Vec<f32> = exact
+                .search_with_distances(&query, 10)
+                .iter()
+                .map(|item| item.0)
+                .collect();
+            for (name, index) in [("vptree", &vptree as &dyn Algorithm), ("mvptree", &mvptree)] {
+                let found: Vec<f32> = index
+                    .search(&query, 10)
+                    .iter()
+                    .map(|id| metric.distance(&query, &data[*id].1))
+                    .collect();
+                assert_eq!(found, expected, "{name} with {metric:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_epsilon_bound() {
+    let data = synthetic::gaussian_clusters(2000, 8, 10, 0.2, 13);
+    let fresh = synthetic::gaussian_clusters(20, 8, 10, 0.2, 14);
+    let exact = Exact::with_metric(&data, METRIC);
+
+    for epsilon in [0.5, 2.0] {
+        let kdtree = KDTree::with_config(
+            &data,
+            KDTreeConfig {
+                epsilon,
+                ..KDTreeConfig::default()
+            },
+        );
+        let vptree = VPTree::with_config(
+            &data,
+            VPTreeConfig {
+                epsilon,
+                ..VPTreeConfig::default()
+            },
+        );
+
+        for (name, index) in [("kdtree", &kdtree as &dyn Algorithm), ("vptree", &vptree)] {
+            for query in queries(&data, &fresh) {
+                let expected = exact.search_with_distances(&query, 10);
+                let result = index.search(&query, 10);
+                assert_eq!(result.len(), 10);
+
+                // the i-th result is within (1 + epsilon) of the i-th true neighbor
+                let mut distances: Vec<f32> = result
+                    .iter()
+                    .map(|id| distance(&query, &data[*id].1))
+                    .collect();
+                distances.sort_by(f32::total_cmp);
+                for (found, truth) in distances.iter().zip(&expected) {
+                    assert!(
+                        found.sqrt() <= (1.0 + epsilon) * truth.0.sqrt() + 1e-5,
+                        "{name} with epsilon = {epsilon}: {found} vs {}",
+                        truth.0
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_kdtree_updates() {
+    let initial = synthetic::gaussian_clusters(300, 4, 5, 0.2, 17);
+    let extra = synthetic::gaussian_clusters(700, 4, 5, 0.2, 18);
+    let queries = synthetic::uniform(10, 4, 19);
+
+    let mut kdtree = KDTree::load(&initial);
+    let mut live: HashMap<VectorID, Vec<f32>> = initial.into_iter().collect();
+
+    let mut rng = StdRng::seed_from_u64(20);
+    for (step, (_, vector)) in extra.into_iter().enumerate() {
+        // ids past the initial ones are new, the others replace points
+        let id = rng.gen_range(0..1000);
+        kdtree.insert(id, &vector);
+        live.insert(id, vector);
+
+        if step % 2 == 0 {
+            let id = rng.gen_range(0..1000);
+            assert_eq!(kdtree.remove(id), live.remove(&id).is_some(), "remove {id}");
+        }
+
+        if step % 50 == 0 {
+            assert_eq!(kdtree.len(), live.len());
+            for (_, query) in &queries {
+                let mut expected: Vec<f32> = live.values().map(|v| distance(query, v)).collect();
+                expected.sort_by(f32::total_cmp);
+                expected.truncate(10);
+
+                let found: Vec<f32> = kdtree
+                    .search(query, 10)
+                    .iter()
+                    .map(|id| distance(query, &live[id]))
+                    .collect();
+                assert_eq!(found, expected, "after {step} updates");
+            }
+        }
+    }
+
+    let ids: Vec<VectorID> = live.keys().copied().collect();
+    for id in ids {
+        assert!(kdtree.remove(id));
+    }
+    assert!(kdtree.is_empty());
+    assert!(kdtree.search(&queries[0].1, 10).is_empty());
+}
+
+#[test]
+fn test_kdtree_starts_empty() {
+    let points = synthetic::gaussian_clusters(500, 3, 4, 0.2, 21);
+    let queries = synthetic::uniform(10, 3, 22);
+
+    let mut kdtree = KDTree::new(3, KDTreeConfig::default());
+    assert!(kdtree.search(&queries[0].1, 10).is_empty());
+
+    let mut live: HashMap<VectorID, Vec<f32>> = HashMap::new();
+    for (id, vector) in points {
+        kdtree.insert(id, &vector);
+        live.insert(id, vector);
+        if id % 3 == 0 {
+            assert!(kdtree.remove(id / 2));
+            live.remove(&(id / 2));
+        }
+    }
+    assert_eq!(kdtree.len(), live.len());
+
+    for (_, query) in &queries {
+        let mut expected: Vec<f32> = live.values().map(|v| distance(query, v)).collect();
+        expected.sort_by(f32::total_cmp);
+        expected.truncate(10);
+
+        let found: Vec<f32> = kdtree
+            .search(query, 10)
+            .iter()
+            .map(|id| distance(query, &live[id]))
+            .collect();
+        assert_eq!(found, expected);
+    }
+}